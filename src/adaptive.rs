@@ -0,0 +1,225 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::ImageFormat;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// environment variable turning adaptive upload quality on
+pub const ADAPTIVE_QUALITY_VAR: &str = "SECURITY_CAM_ADAPTIVE_QUALITY";
+
+/// how often the backlog is checked and the quality adjusted
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// how many windows in a row the queue has to stay empty before quality goes back up a level
+const RECOVER_WINDOWS: u32 = 5;
+
+/// jpeg quality and resolution frames are re-encoded with, and how many of them are sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Quality {
+    pub jpeg_quality: u8,
+
+    /// width and height as a percentage of the camera's
+    pub scale_percent: u32,
+
+    /// only every nth frame is sent, 1 sends them all
+    pub keep_every: usize,
+}
+
+impl Quality {
+    /// whether the frame at index in the recording is sent at this quality
+    pub fn keeps(&self, index: usize) -> bool {
+        index % self.keep_every.max(1) == 0
+    }
+}
+
+/// quality levels from best to worst, None sends the camera's frames untouched
+const LEVELS: [Option<Quality>; 5] = [
+    None,
+    Some(Quality {
+        jpeg_quality: 75,
+        scale_percent: 100,
+        keep_every: 1,
+    }),
+    Some(Quality {
+        jpeg_quality: 60,
+        scale_percent: 75,
+        keep_every: 1,
+    }),
+    Some(Quality {
+        jpeg_quality: 50,
+        scale_percent: 50,
+        keep_every: 1,
+    }),
+    Some(Quality {
+        jpeg_quality: 50,
+        scale_percent: 50,
+        keep_every: 2,
+    }),
+];
+
+/// a point in the recording where the quality was changed
+#[derive(Clone, Debug, Serialize)]
+pub struct QualityChange {
+    /// index of the first frame sent with the new quality
+    pub frame: usize,
+
+    /// None means the camera's frames are sent untouched
+    pub quality: Option<Quality>,
+
+    /// bytes per second the upload managed over the window before the change
+    pub throughput: u64,
+}
+
+/// picks the quality for each frame of a recording from how far behind the upload is
+pub struct AdaptiveQuality {
+    level: usize,
+    frames: usize,
+    window_start: Instant,
+    window_bytes: u64,
+    calm_windows: u32,
+    pub changes: Vec<QualityChange>,
+}
+
+impl Default for AdaptiveQuality {
+    fn default() -> Self {
+        AdaptiveQuality {
+            level: 0,
+            frames: 0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+            calm_windows: 0,
+            changes: Vec::new(),
+        }
+    }
+}
+
+impl AdaptiveQuality {
+    /// the quality to send the next frame with, None to send it as is
+    pub fn quality(&self) -> Option<Quality> {
+        LEVELS[self.level]
+    }
+
+    /// records that a frame of sent_bytes went to the upload while backlog frames were still waiting
+    /// in a queue of capacity frames
+    pub fn observe(&mut self, sent_bytes: usize, backlog: usize, capacity: usize) {
+        self.observe_at(Instant::now(), sent_bytes, backlog, capacity)
+    }
+
+    fn observe_at(&mut self, now: Instant, sent_bytes: usize, backlog: usize, capacity: usize) {
+        self.frames += 1;
+        self.window_bytes += sent_bytes as u64;
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < WINDOW {
+            return;
+        }
+        let throughput = (self.window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+        self.window_start = now;
+        self.window_bytes = 0;
+
+        if backlog * 2 >= capacity {
+            self.calm_windows = 0;
+            if self.level + 1 < LEVELS.len() {
+                self.change_level(self.level + 1, throughput);
+            }
+        } else if backlog == 0 {
+            self.calm_windows += 1;
+            if self.calm_windows >= RECOVER_WINDOWS && self.level > 0 {
+                self.calm_windows = 0;
+                self.change_level(self.level - 1, throughput);
+            }
+        } else {
+            self.calm_windows = 0;
+        }
+    }
+
+    fn change_level(&mut self, level: usize, throughput: u64) {
+        self.level = level;
+        info!(
+            throughput_kbs = throughput / 1000,
+            frame = self.frames,
+            "sending with {:?}",
+            LEVELS[level]
+        );
+        self.changes.push(QualityChange {
+            frame: self.frames,
+            quality: LEVELS[level],
+            throughput,
+        });
+    }
+}
+
+/// decodes a jpeg frame and encodes it again at a lower quality and resolution
+pub fn reencode(jpeg: &[u8], quality: Quality) -> Result<Vec<u8>, image::ImageError> {
+    let mut image = image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg)?;
+    if quality.scale_percent < 100 {
+        let width = (image.width() * quality.scale_percent / 100).max(1);
+        let height = (image.height() * quality.scale_percent / 100).max(1);
+        image = image.resize_exact(width, height, FilterType::Triangle);
+    }
+    let mut reencoded = Vec::new();
+    JpegEncoder::new_with_quality(&mut reencoded, quality.jpeg_quality)
+        .encode_image(&image.to_rgb8())?;
+    Ok(reencoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    #[test]
+    fn test_degrade_and_recover() {
+        let mut adaptive = AdaptiveQuality::default();
+        let mut now = adaptive.window_start;
+        let mut tick = |adaptive: &mut AdaptiveQuality, backlog| {
+            now += WINDOW;
+            adaptive.observe_at(now, 100_000, backlog, 10);
+        };
+
+        tick(&mut adaptive, 8);
+        tick(&mut adaptive, 8);
+        assert_eq!(adaptive.quality(), LEVELS[2]);
+
+        // a partly full queue holds the quality where it is
+        for _ in 0..10 {
+            tick(&mut adaptive, 2);
+        }
+        assert_eq!(adaptive.quality(), LEVELS[2]);
+
+        for _ in 0..RECOVER_WINDOWS {
+            tick(&mut adaptive, 0);
+        }
+        assert_eq!(adaptive.quality(), LEVELS[1]);
+        assert_eq!(adaptive.changes.len(), 3);
+        assert_eq!(adaptive.changes[0].throughput, 100_000);
+    }
+
+    #[test]
+    fn test_reencode_scales_down() {
+        let image = RgbImage::from_fn(64, 48, |x, y| Rgb([x as u8 * 4, y as u8 * 5, 128]));
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 100)
+            .encode_image(&image)
+            .unwrap();
+
+        let reencoded = reencode(&jpeg, LEVELS[3].unwrap()).unwrap();
+        assert!(reencoded.len() < jpeg.len());
+        let decoded = image::load_from_memory(&reencoded).unwrap();
+        assert_eq!(decoded.dimensions(), (32, 24));
+    }
+
+    #[test]
+    fn test_lowest_level_halves_frame_rate() {
+        let mut adaptive = AdaptiveQuality::default();
+        let mut now = adaptive.window_start;
+        for _ in 0..LEVELS.len() {
+            now += WINDOW;
+            adaptive.observe_at(now, 100_000, 10, 10);
+        }
+        let quality = adaptive.quality().unwrap();
+        assert_eq!(quality, LEVELS[LEVELS.len() - 1].unwrap());
+        let kept = (0..10).filter(|index| quality.keeps(*index)).count();
+        assert_eq!(kept, 5);
+    }
+}
@@ -0,0 +1,229 @@
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing::info;
+
+use crate::schedule::TimeWindow;
+
+/// environment variable holding `;` separated `<days> <HH:MM>-<HH:MM> <mode>` rules,
+/// eg. `mon-fri 08:00-18:00 ignore; sat-sun 10:00-16:00 local`
+pub const ARM_SCHEDULE_VAR: &str = "SECURITY_CAM_ARM_SCHEDULE";
+
+/// environment variable holding the mode outside of any scheduled window, upload by default
+pub const ARM_DEFAULT_VAR: &str = "SECURITY_CAM_ARM_DEFAULT";
+
+/// environment variable holding the timezone the schedule is written in, eg. `Europe/London`.
+/// the system's local time by default
+pub const TIMEZONE_VAR: &str = "SECURITY_CAM_TIMEZONE";
+
+/// what happens to a motion event
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArmMode {
+    /// recorded and uploaded
+    #[default]
+    Upload,
+
+    /// recorded to an encrypted file on the camera but not uploaded
+    Local,
+
+    /// not recorded at all
+    Ignore,
+}
+
+impl FromStr for ArmMode {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "upload" => Ok(ArmMode::Upload),
+            "local" => Ok(ArmMode::Local),
+            "ignore" => Ok(ArmMode::Ignore),
+            other => Err(format!("unknown mode {other}, expected upload, local or ignore").into()),
+        }
+    }
+}
+
+/// the mode by time of day and day of week
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArmSchedule {
+    /// None for the system's local time
+    pub timezone: Option<Tz>,
+
+    /// mode outside of any scheduled window
+    pub base: ArmMode,
+
+    /// the first rule whose window contains the current time decides the mode
+    pub rules: Vec<(TimeWindow, ArmMode)>,
+}
+
+impl ArmSchedule {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Self::parse(
+            std::env::var(TIMEZONE_VAR).ok().as_deref(),
+            std::env::var(ARM_DEFAULT_VAR).ok().as_deref(),
+            std::env::var(ARM_SCHEDULE_VAR)
+                .ok()
+                .as_deref()
+                .unwrap_or(""),
+        )
+    }
+
+    /// builds a schedule from the same strings as the environment variables
+    pub fn parse(
+        timezone: Option<&str>,
+        base: Option<&str>,
+        rules: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        let timezone = match timezone.map(str::trim).filter(|tz| !tz.is_empty()) {
+            Some(tz) => Some(
+                tz.parse::<Tz>()
+                    .map_err(|_| format!("unknown timezone {tz}, expected eg. Europe/London"))?,
+            ),
+            None => None,
+        };
+        let base = match base {
+            Some(base) => base.parse()?,
+            None => ArmMode::default(),
+        };
+        let rules = rules
+            .split(';')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(parse_rule)
+            .collect::<Result<_, _>>()?;
+        Ok(ArmSchedule {
+            timezone,
+            base,
+            rules,
+        })
+    }
+
+    pub fn mode_at(&self, now: DateTime<Utc>) -> ArmMode {
+        let local = self.local_time(now);
+        self.rules
+            .iter()
+            .find(|(window, _)| window.contains(local))
+            .map(|(_, mode)| *mode)
+            .unwrap_or(self.base)
+    }
+
+    fn local_time(&self, now: DateTime<Utc>) -> NaiveDateTime {
+        match self.timezone {
+            Some(tz) => now.with_timezone(&tz).naive_local(),
+            None => now.with_timezone(&Local).naive_local(),
+        }
+    }
+}
+
+fn parse_rule(rule: &str) -> Result<(TimeWindow, ArmMode), Box<dyn Error>> {
+    let (window, mode) = rule
+        .trim()
+        .rsplit_once(char::is_whitespace)
+        .ok_or_else(|| format!("expected `<days> <HH:MM>-<HH:MM> <mode>`, got {rule}"))?;
+    Ok((window.parse()?, mode.parse()?))
+}
+
+/// a schedule as it comes in the camera's configuration from the server, see remote_config
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct ArmScheduleDocument {
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub schedule: String,
+}
+
+impl TryFrom<ArmScheduleDocument> for ArmSchedule {
+    type Error = Box<dyn Error>;
+
+    fn try_from(document: ArmScheduleDocument) -> Result<Self, Self::Error> {
+        ArmSchedule::parse(
+            document.timezone.as_deref(),
+            document.default.as_deref(),
+            &document.schedule,
+        )
+    }
+}
+
+/// decides what happens to motion: the schedule, unless someone has set the mode by hand
+#[derive(Debug, Default)]
+pub struct Arming {
+    schedule: Mutex<ArmSchedule>,
+
+    /// set by arm and disarm commands, takes precedence over the schedule until cleared
+    manual: Mutex<Option<ArmMode>>,
+}
+
+impl Arming {
+    pub fn mode(&self) -> ArmMode {
+        self.mode_at(Utc::now())
+    }
+
+    pub fn mode_at(&self, now: DateTime<Utc>) -> ArmMode {
+        let manual = *self.manual.lock().unwrap();
+        manual.unwrap_or_else(|| self.schedule.lock().unwrap().mode_at(now))
+    }
+
+    /// sets the mode by hand, or goes back to following the schedule with None
+    pub fn set_override(&self, mode: Option<ArmMode>) {
+        info!(?mode, "arm mode override set");
+        *self.manual.lock().unwrap() = mode;
+    }
+
+    pub fn overridden(&self) -> Option<ArmMode> {
+        *self.manual.lock().unwrap()
+    }
+
+    pub fn set_schedule(&self, schedule: ArmSchedule) {
+        *self.schedule.lock().unwrap() = schedule;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(day: u32, hour: u32) -> DateTime<Utc> {
+        // 2024-01-01 was a monday
+        Utc.with_ymd_and_hms(2024, 1, day, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_schedule_in_timezone() {
+        let schedule = ArmSchedule::parse(
+            Some("America/New_York"),
+            None,
+            "mon-fri 08:00-18:00 ignore; sat-sun 10:00-16:00 local",
+        )
+        .unwrap();
+        // 12:00 utc is 07:00 in new york, before business hours
+        assert_eq!(schedule.mode_at(utc(1, 12)), ArmMode::Upload);
+        assert_eq!(schedule.mode_at(utc(1, 14)), ArmMode::Ignore);
+        // 22:00 utc on friday is still business hours in new york
+        assert_eq!(schedule.mode_at(utc(5, 22)), ArmMode::Ignore);
+        assert_eq!(schedule.mode_at(utc(6, 16)), ArmMode::Local);
+    }
+
+    #[test]
+    fn test_override() {
+        let arming = Arming::default();
+        arming.set_schedule(ArmSchedule::parse(Some("UTC"), Some("ignore"), "").unwrap());
+        assert_eq!(arming.mode_at(utc(1, 12)), ArmMode::Ignore);
+        arming.set_override(Some(ArmMode::Upload));
+        assert_eq!(arming.mode_at(utc(1, 12)), ArmMode::Upload);
+        arming.set_override(None);
+        assert_eq!(arming.mode_at(utc(1, 12)), ArmMode::Ignore);
+    }
+
+    #[test]
+    fn test_invalid_schedule() {
+        ArmSchedule::parse(Some("Mars/Olympus_Mons"), None, "").unwrap_err();
+        ArmSchedule::parse(None, Some("armed"), "").unwrap_err();
+        ArmSchedule::parse(None, None, "mon-fri 08:00-18:00 off").unwrap_err();
+    }
+}
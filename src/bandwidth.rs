@@ -0,0 +1,271 @@
+use chrono::{Local, NaiveDateTime};
+use security_cam_common::futures::{Stream, StreamExt};
+use security_cam_common::shuttle_runtime::tokio;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::schedule::TimeWindow;
+
+/// environment variable holding the upload limit for everything this process sends, eg. `2mbit`
+pub const GLOBAL_LIMIT_VAR: &str = "SECURITY_CAM_BANDWIDTH_LIMIT";
+
+/// environment variable holding `;` separated `<days> <HH:MM>-<HH:MM> <limit>` rules for the global limit,
+/// eg. `mon-fri 09:00-17:00 512kbit; daily 00:00-06:00 unlimited`
+pub const GLOBAL_SCHEDULE_VAR: &str = "SECURITY_CAM_BANDWIDTH_SCHEDULE";
+
+/// like SECURITY_CAM_BANDWIDTH_LIMIT but for each camera's uploads on their own
+pub const CAMERA_LIMIT_VAR: &str = "SECURITY_CAM_CAMERA_BANDWIDTH_LIMIT";
+pub const CAMERA_SCHEDULE_VAR: &str = "SECURITY_CAM_CAMERA_BANDWIDTH_SCHEDULE";
+
+/// an upload limit in bytes per second, which can change with the time of day
+#[derive(Default, Clone, Debug)]
+pub struct RateSchedule {
+    /// limit outside of any scheduled window, None for unlimited
+    pub base: Option<u64>,
+
+    /// the first rule whose window contains the current local time decides the limit
+    pub rules: Vec<(TimeWindow, Option<u64>)>,
+}
+
+impl RateSchedule {
+    fn from_env(limit_var: &str, schedule_var: &str) -> Result<Self, Box<dyn Error>> {
+        let base = match std::env::var(limit_var) {
+            Ok(limit) => parse_rate(&limit).map_err(|e| format!("{limit_var}: {e}"))?,
+            Err(_) => None,
+        };
+        let rules = match std::env::var(schedule_var) {
+            Ok(schedule) => parse_rules(&schedule).map_err(|e| format!("{schedule_var}: {e}"))?,
+            Err(_) => Vec::new(),
+        };
+        Ok(RateSchedule { base, rules })
+    }
+
+    /// the limit in bytes per second at now, None for unlimited
+    pub fn rate_at(&self, now: NaiveDateTime) -> Option<u64> {
+        self.rules
+            .iter()
+            .find(|(window, _)| window.contains(now))
+            .map(|(_, rate)| *rate)
+            .unwrap_or(self.base)
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.base.is_none() && self.rules.iter().all(|(_, rate)| rate.is_none())
+    }
+}
+
+/// parses `unlimited`, a number of bytes per second, or a number followed by KB, MB, kbit or mbit
+pub fn parse_rate(rate: &str) -> Result<Option<u64>, Box<dyn Error>> {
+    let rate = rate.trim().to_ascii_lowercase();
+    if rate == "unlimited" {
+        return Ok(None);
+    }
+    let split = rate
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rate.len());
+    let (number, unit) = rate.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid bandwidth limit {rate}"))?;
+    let bytes_per_second = match unit.trim() {
+        "" | "b" => number,
+        "kb" => number * 1000.0,
+        "mb" => number * 1_000_000.0,
+        "kbit" => number * 1000.0 / 8.0,
+        "mbit" => number * 1_000_000.0 / 8.0,
+        other => return Err(format!("unknown unit {other} in bandwidth limit {rate}").into()),
+    };
+    if bytes_per_second < 1.0 {
+        return Err(format!("bandwidth limit {rate} is too small").into());
+    }
+    Ok(Some(bytes_per_second as u64))
+}
+
+fn parse_rules(schedule: &str) -> Result<Vec<(TimeWindow, Option<u64>)>, Box<dyn Error>> {
+    schedule
+        .split(';')
+        .filter(|rule| !rule.trim().is_empty())
+        .map(|rule| -> Result<(TimeWindow, Option<u64>), Box<dyn Error>> {
+            let (window, rate) = rule
+                .trim()
+                .rsplit_once(char::is_whitespace)
+                .ok_or_else(|| format!("expected `<days> <HH:MM>-<HH:MM> <limit>`, got {rule}"))?;
+            Ok((window.parse()?, parse_rate(rate)?))
+        })
+        .collect()
+}
+
+/// global and per camera upload limits
+#[derive(Default, Clone, Debug)]
+pub struct BandwidthConfig {
+    pub global: RateSchedule,
+    pub per_camera: RateSchedule,
+}
+
+impl BandwidthConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Ok(BandwidthConfig {
+            global: RateSchedule::from_env(GLOBAL_LIMIT_VAR, GLOBAL_SCHEDULE_VAR)?,
+            per_camera: RateSchedule::from_env(CAMERA_LIMIT_VAR, CAMERA_SCHEDULE_VAR)?,
+        })
+    }
+}
+
+/// token bucket holding up to a second's worth of bytes at the current limit.
+/// a chunk bigger than what is left is let through straight away and the debt is waited off afterwards,
+/// which keeps the average rate right without having to split chunks up
+pub struct TokenBucket {
+    schedule: RateSchedule,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(schedule: RateSchedule) -> Self {
+        let tokens = schedule.rate_at(Local::now().naive_local()).unwrap_or(0) as f64;
+        TokenBucket {
+            schedule,
+            state: Mutex::new(BucketState {
+                tokens,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// waits until sending bytes keeps the upload within the limit
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let Some(rate) = self.schedule.rate_at(Local::now().naive_local()) else {
+                return;
+            };
+            let rate = rate as f64;
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            state.tokens = (state.tokens + now.duration_since(state.last_refill).as_secs_f64() * rate)
+                .min(rate);
+            state.last_refill = now;
+            state.tokens -= bytes as f64;
+            if state.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.tokens / rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// hands out the buckets an upload has to go through: the global one and the one for its camera
+pub struct BandwidthLimiter {
+    global: Arc<TokenBucket>,
+    per_camera: RateSchedule,
+    cameras: Mutex<HashMap<String, Arc<TokenBucket>>>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(config: &BandwidthConfig) -> Self {
+        BandwidthLimiter {
+            global: Arc::new(TokenBucket::new(config.global.clone())),
+            per_camera: config.per_camera.clone(),
+            cameras: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// the buckets for an upload from camera_id, skipping any that can never limit anything
+    pub fn buckets_for(&self, camera_id: &str) -> Vec<Arc<TokenBucket>> {
+        let mut buckets = Vec::new();
+        if !self.global.schedule.is_unlimited() {
+            buckets.push(self.global.clone());
+        }
+        if !self.per_camera.is_unlimited() {
+            buckets.push(
+                self.cameras
+                    .lock()
+                    .unwrap()
+                    .entry(camera_id.to_string())
+                    .or_insert_with(|| Arc::new(TokenBucket::new(self.per_camera.clone())))
+                    .clone(),
+            );
+        }
+        buckets
+    }
+}
+
+/// passes the chunks of stream through once every bucket has room for them
+pub fn limit_stream<S>(
+    stream: S,
+    buckets: Vec<Arc<TokenBucket>>,
+) -> impl Stream<Item = Result<Vec<u8>, std::io::Error>> + Send
+where
+    S: Stream<Item = Result<Vec<u8>, std::io::Error>> + Send,
+{
+    stream.then(move |chunk| {
+        let buckets = buckets.clone();
+        async move {
+            if let Ok(chunk) = &chunk {
+                for bucket in &buckets {
+                    bucket.acquire(chunk.len()).await;
+                }
+            }
+            chunk
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use security_cam_common::futures::stream;
+    use security_cam_common::shuttle_runtime::tokio;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("2mbit").unwrap(), Some(250_000));
+        assert_eq!(parse_rate("512 kbit").unwrap(), Some(64_000));
+        assert_eq!(parse_rate("1.5MB").unwrap(), Some(1_500_000));
+        assert_eq!(parse_rate("4000").unwrap(), Some(4000));
+        assert_eq!(parse_rate("unlimited").unwrap(), None);
+        parse_rate("fast").unwrap_err();
+        parse_rate("10 gbit").unwrap_err();
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule = RateSchedule {
+            base: Some(1_000_000),
+            rules: parse_rules("mon-fri 09:00-17:00 512kbit; daily 00:00-06:00 unlimited").unwrap(),
+        };
+        let monday = chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        assert_eq!(
+            schedule.rate_at(monday.and_hms_opt(10, 0, 0).unwrap()),
+            Some(64_000)
+        );
+        assert_eq!(schedule.rate_at(monday.and_hms_opt(3, 0, 0).unwrap()), None);
+        assert_eq!(
+            schedule.rate_at(monday.and_hms_opt(20, 0, 0).unwrap()),
+            Some(1_000_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limit_stream() {
+        let bucket = Arc::new(TokenBucket::new(RateSchedule {
+            base: Some(100_000),
+            rules: Vec::new(),
+        }));
+        let chunks = stream::iter((0..4).map(|_| Ok(vec![0u8; 50_000])));
+        let start = Instant::now();
+        let sent = limit_stream(chunks, vec![bucket])
+            .map(|chunk| chunk.unwrap().len())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(sent.iter().sum::<usize>(), 200_000);
+        // the first 100KB fit in the bucket, the other 100KB take a second
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+}
@@ -0,0 +1,270 @@
+use reqwest::Url;
+use security_cam_common::futures::{Stream, StreamExt};
+use security_cam_common::shuttle_runtime::tokio;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::bandwidth::TokenBucket;
+use crate::framing::FrameEncryptor;
+use crate::keys::KeyPair;
+use crate::metrics::METRICS;
+use crate::networking::KEY_ID_HEADER;
+
+/// how many times a segment is tried before the upload gives up
+const MAX_ATTEMPTS: u32 = 8;
+
+/// wait before the first retry, doubling after every failed attempt up to MAX_RETRY_DELAY
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// a recording uploaded as numbered segments, each acknowledged by the server before the next is sent, so that
+/// a dropped connection only costs the segment that was in flight.
+///
+/// under url (`upload_chunked/<video>/<fps>/`):
+///     PUT <n>         stores segment n, answers with n once it is stored. segments already stored are
+///                     acknowledged again without being replaced
+///     GET status      answers with how many segments are stored
+///     POST finish     the recording is complete, answers like a normal upload does
+///
+/// the segments are a framed upload (see framing) cut between frames, so put back together in order they are
+/// exactly what would have been sent to upload_framed
+pub struct ChunkedUpload {
+    client: reqwest::Client,
+    url: Url,
+    key_id: String,
+    buckets: Vec<Arc<TokenBucket>>,
+
+    /// segments the server has acknowledged, which is also the number of the next one
+    acknowledged: u64,
+}
+
+impl ChunkedUpload {
+    pub fn new(
+        client: reqwest::Client,
+        url: Url,
+        key_id: String,
+        buckets: Vec<Arc<TokenBucket>>,
+    ) -> Self {
+        ChunkedUpload {
+            client,
+            url,
+            key_id,
+            buckets,
+            acknowledged: 0,
+        }
+    }
+
+    /// sends segment as the next segment. after an error the server is asked how far it got before retrying,
+    /// since the segment may have been stored with only its acknowledgement lost
+    pub async fn send_segment(&mut self, segment: &[u8]) -> Result<(), Box<dyn Error>> {
+        let seq = self.acknowledged;
+        for bucket in &self.buckets {
+            bucket.acquire(segment.len()).await;
+        }
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            let error = match self.put_segment(seq, segment).await {
+                Ok(()) => break,
+                Err(e) => e,
+            };
+            if attempt >= MAX_ATTEMPTS {
+                return Err(
+                    format!("segment {seq} failed after {attempt} attempts: {error}").into(),
+                );
+            }
+            warn!(
+                segment = seq,
+                "sending segment: {error}, retrying in {delay:?}"
+            );
+            METRICS.upload_retries.inc();
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+            attempt += 1;
+
+            match self.stored_segments().await {
+                Ok(stored) if stored > seq => break,
+                Ok(stored) if stored < seq => {
+                    return Err(format!(
+                        "server only has {stored} segments but acknowledged {seq}, cant resume"
+                    )
+                    .into())
+                }
+                _ => {}
+            }
+        }
+        self.acknowledged = seq + 1;
+        METRICS.upload_bytes.add(segment.len() as u64);
+        Ok(())
+    }
+
+    async fn put_segment(&self, seq: u64, segment: &[u8]) -> Result<(), Box<dyn Error>> {
+        let ack = self
+            .client
+            .put(self.url.join(&seq.to_string())?)
+            .header(KEY_ID_HEADER, &self.key_id)
+            .body(segment.to_vec())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        if ack.trim() != seq.to_string() {
+            return Err(format!(
+                "server acknowledged {} instead of segment {seq}",
+                ack.trim()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// how many segments the server has stored
+    async fn stored_segments(&self) -> Result<u64, Box<dyn Error>> {
+        let stored = self
+            .client
+            .get(self.url.join("status")?)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(stored.trim().parse()?)
+    }
+
+    /// tells the server every segment has been sent, returning its response
+    pub async fn finish(self) -> Result<String, Box<dyn Error>> {
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            let result = async {
+                let response = self
+                    .client
+                    .post(self.url.join("finish")?)
+                    .header(KEY_ID_HEADER, &self.key_id)
+                    .body(self.acknowledged.to_string())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok::<String, Box<dyn Error>>(response.text().await?)
+            }
+            .await;
+            match result {
+                Ok(response) => return Ok(response),
+                Err(e) if attempt >= MAX_ATTEMPTS => return Err(e),
+                Err(e) => {
+                    warn!("finishing upload: {e}, retrying in {delay:?}");
+                    METRICS.upload_retries.inc();
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// encrypts frames and sends them through upload in segments of segment_frames frames
+pub async fn upload_chunked<S, B>(
+    mut upload: ChunkedUpload,
+    key_pair: KeyPair,
+    frames: S,
+    segment_frames: usize,
+) -> Result<String, Box<dyn Error>>
+where
+    S: Stream<Item = Result<(u64, B), std::io::Error>>,
+    B: AsRef<[u8]>,
+{
+    let (key, salt) = key_pair;
+    let mut encryptor = FrameEncryptor::new(&key);
+    let mut segment = FrameEncryptor::header(salt.as_str());
+    let mut frames_in_segment = 0;
+    let mut frames = Box::pin(frames);
+    while let Some(frame) = frames.next().await {
+        let (timestamp_ms, frame) = frame?;
+        segment.extend(encryptor.encrypt(timestamp_ms, frame.as_ref())?);
+        frames_in_segment += 1;
+        if frames_in_segment >= segment_frames {
+            upload.send_segment(&segment).await?;
+            segment.clear();
+            frames_in_segment = 0;
+        }
+    }
+    if !segment.is_empty() {
+        upload.send_segment(&segment).await?;
+    }
+    upload.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::decrypt_frames;
+    use crate::mock_server::MockServer;
+    use security_cam_common::encryption::generate_key;
+    use security_cam_common::futures::stream;
+    use security_cam_common::shuttle_runtime::tokio;
+
+    fn frames() -> Vec<(u64, Vec<u8>)> {
+        (0..10u8)
+            .map(|i| {
+                (
+                    1_700_000_000_000 + i as u64 * 40,
+                    vec![i; 100 + i as usize * 50],
+                )
+            })
+            .collect()
+    }
+
+    async fn upload(server: &MockServer) -> (String, [u8; 32]) {
+        let key_pair = generate_key("pass").unwrap();
+        let key = key_pair.0;
+        let url = Url::parse(&server.url())
+            .unwrap()
+            .join("upload_chunked/3/25/")
+            .unwrap();
+        let upload = ChunkedUpload::new(reqwest::Client::new(), url, "default".into(), Vec::new());
+        let frames = stream::iter(frames().into_iter().map(Ok));
+        let response = upload_chunked(upload, key_pair, frames, 3).await.unwrap();
+        (response, key)
+    }
+
+    fn assert_uploaded(server: &MockServer, key: [u8; 32]) {
+        let uploaded = server.finished_upload("3/25").unwrap();
+        let decrypted = decrypt_frames(&uploaded, |_| Ok(key)).unwrap();
+        let decrypted = decrypted
+            .into_iter()
+            .map(|frame| (frame.timestamp_ms, frame.frame))
+            .collect::<Vec<_>>();
+        assert_eq!(decrypted, frames());
+    }
+
+    #[tokio::test]
+    async fn test_segments_reassemble() {
+        let server = MockServer::start().await;
+        let (response, key) = upload(&server).await;
+        // 10 frames in segments of 3
+        assert_eq!(response, "4 segments");
+        assert_uploaded(&server, key);
+    }
+
+    #[tokio::test]
+    async fn test_resumes_after_lost_acknowledgement() {
+        let server = MockServer::start().await;
+        // the segment is stored but the connection drops before the server answers, so the client has to
+        // find out from the server that it doesnt need sending again
+        server.drop_before_ack(2);
+        let (_, key) = upload(&server).await;
+        assert_uploaded(&server, key);
+    }
+
+    #[tokio::test]
+    async fn test_resumes_after_dropped_connection() {
+        let server = MockServer::start().await;
+        server.drop_before_store(2);
+        let (_, key) = upload(&server).await;
+        assert_uploaded(&server, key);
+    }
+}
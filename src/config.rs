@@ -0,0 +1,53 @@
+use std::error::Error;
+
+use crate::arming::ArmSchedule;
+use crate::bandwidth::BandwidthConfig;
+use crate::control::ControlConfig;
+use crate::health::HeartbeatConfig;
+use crate::liveview::LiveViewConfig;
+use crate::local_api::LocalApiConfig;
+use crate::motiondetection::DetectionConfig;
+use crate::proxy::ProxyConfig;
+use crate::shutdown::ShutdownConfig;
+use crate::tls::TlsConfig;
+use crate::upload_queue::UploadConfig;
+
+/// environment variable naming this camera, defaults to the video device number
+pub const CAMERA_ID_VAR: &str = "SECURITY_CAM_CAMERA_ID";
+
+/// options for how the client connects to the server and uploads to it, read from SECURITY_CAM_* environment variables
+#[derive(Default, Clone, Debug)]
+pub struct ClientConfig {
+    /// identifies which camera uploads come from
+    pub camera_id: String,
+    pub tls: TlsConfig,
+    pub proxy: ProxyConfig,
+    pub upload: UploadConfig,
+    pub bandwidth: BandwidthConfig,
+    pub detection: DetectionConfig,
+    pub live: LiveViewConfig,
+    pub control: ControlConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub arming: ArmSchedule,
+    pub local_api: LocalApiConfig,
+    pub shutdown: ShutdownConfig,
+}
+
+impl ClientConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Ok(ClientConfig {
+            camera_id: std::env::var(CAMERA_ID_VAR).unwrap_or_default(),
+            tls: TlsConfig::from_env()?,
+            proxy: ProxyConfig::from_env(),
+            upload: UploadConfig::from_env()?,
+            bandwidth: BandwidthConfig::from_env()?,
+            detection: DetectionConfig::from_env()?,
+            live: LiveViewConfig::from_env()?,
+            control: ControlConfig::from_env()?,
+            heartbeat: HeartbeatConfig::from_env()?,
+            arming: ArmSchedule::from_env()?,
+            local_api: LocalApiConfig::from_env()?,
+            shutdown: ShutdownConfig::from_env()?,
+        })
+    }
+}
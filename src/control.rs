@@ -0,0 +1,453 @@
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use reqwest::Url;
+use reqwest_websocket::{Message, RequestBuilderExt};
+use security_cam_common::futures::{SinkExt, StreamExt};
+use security_cam_common::shuttle_runtime::tokio;
+use security_cam_common::shuttle_runtime::tokio::sync::watch;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use crate::arming::ArmMode;
+use crate::framing::encrypt_single;
+use crate::health::CameraStatus;
+use crate::keys::KeyCache;
+use crate::liveview::LiveView;
+use crate::motiondetection::DetectionConfig;
+use crate::networking::KEY_ID_HEADER;
+use crate::remote_config::RemoteConfig;
+
+/// environment variable holding the secret control messages are signed with. without it there is no control channel
+pub const CONTROL_SECRET_VAR: &str = "SECURITY_CAM_CONTROL_SECRET";
+
+/// header the control connection is opened with, holding a random nonce that messages over it are signed with
+pub const CONTROL_NONCE_HEADER: &str = "X-Control-Nonce";
+
+/// environment variable holding how many seconds apart the camera's configuration is fetched from the server
+pub const CONFIG_POLL_SECONDS_VAR: &str = "SECURITY_CAM_CONFIG_POLL_SECONDS";
+
+/// version of the control messages this client understands, messages with any other version are refused
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// wait before reconnecting a dropped control connection, doubling up to MAX_RECONNECT_DELAY
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Debug)]
+pub struct ControlConfig {
+    /// signs control messages and configuration documents from the server
+    pub secret: Option<String>,
+
+    /// how often the camera's configuration is fetched, see remote_config
+    pub config_poll_interval: Duration,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig {
+            secret: None,
+            config_poll_interval: Duration::from_secs(300),
+        }
+    }
+}
+
+impl ControlConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let mut config = ControlConfig {
+            secret: std::env::var(CONTROL_SECRET_VAR)
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+            ..Default::default()
+        };
+        if let Ok(seconds) = std::env::var(CONFIG_POLL_SECONDS_VAR) {
+            let seconds: u64 = seconds
+                .trim()
+                .parse()
+                .ok()
+                .filter(|seconds| *seconds > 0)
+                .ok_or_else(|| format!("{CONFIG_POLL_SECONDS_VAR} must be a positive integer"))?;
+            config.config_poll_interval = Duration::from_secs(seconds);
+        }
+        Ok(config)
+    }
+}
+
+/// what the server can tell the camera to do
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// overrides the arm schedule with mode, upload if it is left out
+    Arm {
+        #[serde(default)]
+        mode: Option<ArmMode>,
+    },
+
+    /// overrides the arm schedule to ignore motion, same as arm with mode ignore
+    Disarm,
+
+    /// drops the override from arm or disarm, going back to the arm schedule
+    ResumeSchedule,
+
+    /// upload the current frame to `upload_snapshot/<camera>`
+    Snapshot,
+
+    StartLiveView {
+        #[serde(default)]
+        fps: Option<u32>,
+    },
+    StopLiveView,
+
+    /// fetch the camera's configuration from the server straight away, see remote_config
+    ReloadConfig,
+
+    /// change how much movement it takes to start a recording, leaving out a field keeps its current value
+    SetSensitivity {
+        #[serde(default)]
+        threshold: Option<u8>,
+        #[serde(default)]
+        min_score: Option<u32>,
+    },
+}
+
+/// a command as it comes over the wire.
+/// body is the command as json and mac is hex HMAC-SHA256 of `<version>.<nonce>.<id>.<body>` with the control
+/// secret, where nonce is the one the connection was opened with
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    version: u32,
+    id: u64,
+    body: String,
+    #[serde(default)]
+    mac: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckStatus {
+    Ok,
+    Error,
+
+    /// the mac was missing or wrong, or the id wasnt higher than the last one
+    Unauthorized,
+
+    /// a version or command this client doesnt know
+    Unsupported,
+}
+
+/// sent back for every message the server sends, id is None if the message couldnt be read at all
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Ack {
+    pub version: u32,
+    pub id: Option<u64>,
+    pub status: AckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl Ack {
+    fn new(id: Option<u64>, status: AckStatus, message: Option<String>) -> Self {
+        Ack {
+            version: PROTOCOL_VERSION,
+            id,
+            status,
+            message,
+        }
+    }
+}
+
+/// checks a message from the server and takes the command out of it. the mac covers the connection's nonce and
+/// ids have to go up with every message, so a recorded message cant be played back on the same connection or a
+/// later one. last_id is the id of the last message accepted
+pub fn open_message(
+    text: &str,
+    secret: &str,
+    nonce: &str,
+    last_id: Option<u64>,
+) -> Result<(u64, Command), Ack> {
+    let envelope: Envelope = serde_json::from_str(text)
+        .map_err(|e| Ack::new(None, AckStatus::Unsupported, Some(e.to_string())))?;
+    let id = envelope.id;
+    if envelope.version != PROTOCOL_VERSION {
+        return Err(Ack::new(
+            Some(id),
+            AckStatus::Unsupported,
+            Some(format!("only version {PROTOCOL_VERSION} is supported")),
+        ));
+    }
+    let signed = format!("{}.{nonce}.{id}.{}", envelope.version, envelope.body);
+    if !mac_matches(secret, &signed, envelope.mac.as_deref()) {
+        return Err(Ack::new(Some(id), AckStatus::Unauthorized, None));
+    }
+    if last_id.is_some_and(|last_id| id <= last_id) {
+        return Err(Ack::new(
+            Some(id),
+            AckStatus::Unauthorized,
+            Some("message id has already been used".to_string()),
+        ));
+    }
+    let command = serde_json::from_str(&envelope.body)
+        .map_err(|e| Ack::new(Some(id), AckStatus::Unsupported, Some(e.to_string())))?;
+    Ok((id, command))
+}
+
+/// carries out commands from the server.
+///
+/// keeps a websocket open to `control/<camera>`, which the server sends Envelopes over. every message is answered
+/// with an Ack, in order
+pub struct Controller {
+    pub(crate) client: reqwest::Client,
+    pub(crate) addr: Url,
+    pub(crate) camera_id: String,
+    pub(crate) key_id: String,
+    pub(crate) keys: Arc<KeyCache>,
+    pub(crate) config: ControlConfig,
+    pub(crate) live: LiveView,
+    pub(crate) detection: watch::Sender<DetectionConfig>,
+    pub(crate) remote_config: RemoteConfig,
+
+    /// the arm mode is overridden by the server
+    pub(crate) status: Arc<CameraStatus>,
+}
+
+impl Controller {
+    /// keeps the control connection up for as long as the client runs. without a control secret commands
+    /// couldnt be told apart from anyone elses, so there is no control channel at all
+    pub async fn run(mut self) {
+        if self.config.secret.is_none() {
+            warn!("{CONTROL_SECRET_VAR} isnt set, not opening the control channel");
+            return;
+        }
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            match self.connect().await {
+                Ok(()) => {
+                    info!("control connection closed by the server");
+                    delay = RECONNECT_DELAY;
+                }
+                Err(e) => error!("control connection: {e}"),
+            }
+            // nothing started over a connection outlives it
+            self.live.stop();
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    async fn connect(&mut self) -> Result<(), Box<dyn Error>> {
+        let secret = self.config.secret.clone().ok_or("no control secret")?;
+        let url = self.addr.join("control/")?.join(&self.camera_id)?;
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let nonce = hex(&nonce);
+        let mut control = self
+            .client
+            .get(url)
+            .header(CONTROL_NONCE_HEADER, &nonce)
+            .upgrade()
+            .send()
+            .await?
+            .into_websocket()
+            .await?;
+        info!("control connection open");
+        let mut last_id = None;
+        while let Some(message) = control.next().await {
+            let text = match message? {
+                Message::Text(text) => text,
+                Message::Close { .. } => break,
+                _ => continue,
+            };
+            let ack = match open_message(&text, &secret, &nonce, last_id) {
+                Ok((id, command)) => {
+                    last_id = Some(id);
+                    info!(id, ?command, "control command");
+                    match self.handle(command).await {
+                        Ok(status) => Ack::new(Some(id), status, None),
+                        Err(e) => Ack::new(Some(id), AckStatus::Error, Some(e.to_string())),
+                    }
+                }
+                Err(ack) => ack,
+            };
+            if ack.status != AckStatus::Ok {
+                warn!(?ack, "control message refused");
+            }
+            control
+                .send(Message::Text(serde_json::to_string(&ack)?))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle(&mut self, command: Command) -> Result<AckStatus, Box<dyn Error>> {
+        match command {
+            Command::Arm { mode } => self
+                .status
+                .arming
+                .set_override(Some(mode.unwrap_or_default())),
+            Command::Disarm => self.status.arming.set_override(Some(ArmMode::Ignore)),
+            Command::ResumeSchedule => self.status.arming.set_override(None),
+            Command::Snapshot => self.send_snapshot().await?,
+            Command::StartLiveView { fps } => self.live.start(fps),
+            Command::StopLiveView => self.live.stop(),
+            Command::ReloadConfig => {
+                self.remote_config.reload().await?;
+            }
+            Command::SetSensitivity {
+                threshold,
+                min_score,
+            } => {
+                self.detection.send_modify(|config| {
+                    if let Some(threshold) = threshold {
+                        config.threshold = threshold;
+                    }
+                    if let Some(min_score) = min_score {
+                        config.min_score = min_score;
+                    }
+                });
+            }
+        }
+        Ok(AckStatus::Ok)
+    }
+
+    /// uploads the latest frame, encrypted like a framed upload with the frame as its only frame
+    async fn send_snapshot(&self) -> Result<(), Box<dyn Error>> {
+        let frame = self
+            .live
+            .latest_frame()
+            .ok_or("no frame has been captured yet")?;
+        let body = encrypt_single(self.keys.take().await?, frame.timestamp_ms, &frame.jpeg)?;
+        self.client
+            .post(self.addr.join("upload_snapshot/")?.join(&self.camera_id)?)
+            .header(KEY_ID_HEADER, &self.key_id)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// whether mac is the hex HMAC-SHA256 of signed with secret
+pub(crate) fn mac_matches(secret: &str, signed: &str, mac: Option<&str>) -> bool {
+    let (Some(mac), Ok(mut hmac)) = (
+        mac.and_then(from_hex),
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()),
+    ) else {
+        return false;
+    };
+    hmac.update(signed.as_bytes());
+    hmac.verify_slice(&mac).is_ok()
+}
+
+/// the hex HMAC-SHA256 of signed with secret, as the server signs messages
+#[cfg(test)]
+pub(crate) fn sign(secret: &str, signed: &str) -> String {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    hmac.update(signed.as_bytes());
+    hex(&hmac.finalize().into_bytes())
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u64, body: &str, secret: &str, nonce: &str) -> String {
+        let mac = sign(secret, &format!("1.{nonce}.{id}.{body}"));
+        serde_json::json!({ "version": 1, "id": id, "body": body, "mac": mac }).to_string()
+    }
+
+    #[test]
+    fn test_mac_matches() {
+        // rfc 4231 test case 2
+        let mac = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        let signed = "what do ya want for nothing?";
+        assert!(mac_matches("Jefe", signed, Some(mac)));
+        assert!(mac_matches("Jefe", signed, Some(&mac.to_ascii_uppercase())));
+        assert!(!mac_matches("Jefe", "what did ya want?", Some(mac)));
+        assert!(!mac_matches("Jefe", signed, Some(&mac[..62])));
+        assert!(!mac_matches("Jefe", signed, None));
+    }
+
+    #[test]
+    fn test_open_message() {
+        let body = r#"{"type":"set_sensitivity","min_score":20}"#;
+        assert_eq!(
+            open_message(&message(3, body, "secret", "n1"), "secret", "n1", Some(2)),
+            Ok((
+                3,
+                Command::SetSensitivity {
+                    threshold: None,
+                    min_score: Some(20)
+                }
+            ))
+        );
+        let live = r#"{"type":"start_live_view"}"#;
+        assert_eq!(
+            open_message(&message(1, live, "secret", "n1"), "secret", "n1", None),
+            Ok((1, Command::StartLiveView { fps: None }))
+        );
+        let arm = r#"{"type":"arm","mode":"local"}"#;
+        assert_eq!(
+            open_message(&message(1, arm, "secret", "n1"), "secret", "n1", None),
+            Ok((
+                1,
+                Command::Arm {
+                    mode: Some(ArmMode::Local)
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_refused_messages() {
+        let body = r#"{"type":"disarm"}"#;
+        let status = |text: &str, last_id| {
+            open_message(text, "secret", "n1", last_id)
+                .unwrap_err()
+                .status
+        };
+        assert_eq!(
+            status(&message(1, body, "wrong", "n1"), None),
+            AckStatus::Unauthorized
+        );
+        let unsigned = format!(r#"{{"version":1,"id":1,"body":{body:?}}}"#);
+        assert_eq!(status(&unsigned, None), AckStatus::Unauthorized);
+        // replayed on the same connection, and on a later one
+        assert_eq!(
+            status(&message(4, body, "secret", "n1"), Some(4)),
+            AckStatus::Unauthorized
+        );
+        assert_eq!(
+            status(&message(1, body, "secret", "n0"), None),
+            AckStatus::Unauthorized
+        );
+        assert_eq!(
+            status(
+                &message(1, r#"{"type":"self_destruct"}"#, "secret", "n1"),
+                None
+            ),
+            AckStatus::Unsupported
+        );
+        let newer = r#"{"version":2,"id":1,"body":"{}"}"#;
+        assert_eq!(status(newer, None), AckStatus::Unsupported);
+        assert_eq!(status("not json", None), AckStatus::Unsupported);
+    }
+}
@@ -0,0 +1,225 @@
+use std::error::Error;
+use std::fs;
+use std::io::IsTerminal;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// environment variable holding the account username
+pub const USERNAME_VAR: &str = "SECURITY_CAM_USERNAME";
+
+/// environment variable holding the account password
+pub const PASSWORD_VAR: &str = "SECURITY_CAM_PASSWORD";
+
+/// environment variable holding the path to a secrets file
+pub const SECRETS_FILE_VAR: &str = "SECURITY_CAM_SECRETS_FILE";
+
+/// environment variable holding the passphrase the video is encrypted with
+pub const ENCRYPTION_PASSWORD_VAR: &str = "SECURITY_CAM_ENCRYPTION_PASSWORD";
+
+/// environment variable holding the path to a raw key file, used instead of an encryption passphrase
+pub const KEY_FILE_VAR: &str = "SECURITY_CAM_KEY_FILE";
+
+/// environment variable holding the identifier of the encryption passphrase or key
+pub const KEY_ID_VAR: &str = "SECURITY_CAM_KEY_ID";
+
+/// key id sent with uploads when none is configured
+pub const DEFAULT_KEY_ID: &str = "default";
+
+/// login credentials for the server, and the passphrase used for end to end encryption
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+
+    /// never sent to the server, only used to encrypt the video
+    pub encryption: EncryptionSecret,
+
+    /// sent along with each upload so the right passphrase can be picked when decrypting
+    pub key_id: String,
+}
+
+/// what the video encryption keys come from
+pub enum EncryptionSecret {
+    /// keys are derived from a passphrase
+    Passphrase(String),
+
+    /// a file holding a random 256 bit key, see keys::generate_key_file
+    KeyFile(PathBuf),
+}
+
+impl Credentials {
+    /// resolves the credentials, in order of precedence, from the environment, from the secrets file
+    /// pointed to by SECURITY_CAM_SECRETS_FILE, or by prompting on the terminal.
+    /// a username given on the command line overrides the one found in the environment or the secrets file
+    pub fn load(username: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let secrets = match std::env::var(SECRETS_FILE_VAR) {
+            Ok(path) => read_secrets_file(&path)?,
+            Err(_) => SecretsFile::default(),
+        };
+
+        let username = match username {
+            Some(username) => username.to_string(),
+            None => match std::env::var(USERNAME_VAR).ok().or(secrets.username) {
+                Some(username) => username,
+                None => prompt_line("username: ")?,
+            },
+        };
+
+        // dont leave the password lying around for any child processes
+        let env_password = std::env::var(PASSWORD_VAR).ok();
+        std::env::remove_var(PASSWORD_VAR);
+        let password = match env_password.or(secrets.password) {
+            Some(password) => password,
+            None => prompt_password("password: ")?,
+        };
+
+        let env_encryption_password = std::env::var(ENCRYPTION_PASSWORD_VAR).ok();
+        std::env::remove_var(ENCRYPTION_PASSWORD_VAR);
+        let key_file = std::env::var(KEY_FILE_VAR).ok().or(secrets.key_file);
+        let encryption = match (key_file, env_encryption_password.or(secrets.encryption_password)) {
+            (Some(key_file), _) => EncryptionSecret::KeyFile(PathBuf::from(key_file)),
+            (None, Some(encryption_password)) => EncryptionSecret::Passphrase(encryption_password),
+            (None, None) if std::io::stdin().is_terminal() => {
+                let encryption_password =
+                    prompt_password("encryption passphrase (leave empty to reuse the password): ")?;
+                if encryption_password.is_empty() {
+                    warn_shared_password();
+                    EncryptionSecret::Passphrase(password.clone())
+                } else {
+                    EncryptionSecret::Passphrase(encryption_password)
+                }
+            }
+            (None, None) => {
+                warn_shared_password();
+                EncryptionSecret::Passphrase(password.clone())
+            }
+        };
+        let key_id = std::env::var(KEY_ID_VAR)
+            .ok()
+            .or(secrets.key_id)
+            .unwrap_or_else(|| DEFAULT_KEY_ID.to_string());
+
+        Ok(Credentials {
+            username,
+            password,
+            encryption,
+            key_id,
+        })
+    }
+}
+
+fn warn_shared_password() {
+    warn!(
+        "no encryption passphrase or key file set with {ENCRYPTION_PASSWORD_VAR} or {KEY_FILE_VAR}, the login password will be used, so the server can decrypt the video"
+    );
+}
+
+/// the contents of a secrets file, any of which may be missing
+#[derive(Default, Debug)]
+pub struct SecretsFile {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub encryption_password: Option<String>,
+    pub key_file: Option<String>,
+    pub key_id: Option<String>,
+}
+
+/// reads a secrets file made of `key = value` lines, refusing it if anyone other than the owner or group can access it
+pub fn read_secrets_file(path: impl AsRef<Path>) -> Result<SecretsFile, Box<dyn Error>> {
+    let path = path.as_ref();
+    check_permissions(path)?;
+    Ok(parse_secrets(&fs::read_to_string(path)?))
+}
+
+/// returns an error if the file at path is readable or writable by other users
+pub fn check_permissions(path: &Path) -> Result<(), Box<dyn Error>> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o007 != 0 {
+        return Err(format!(
+            "refusing to use {}: it is accessible by other users (mode {:o}), run chmod 600 on it",
+            path.display(),
+            mode & 0o777
+        )
+        .into());
+    }
+    if mode & 0o070 != 0 {
+        warn!(
+            "{} is accessible by its group (mode {:o})",
+            path.display(),
+            mode & 0o777
+        );
+    }
+    Ok(())
+}
+
+fn parse_secrets(contents: &str) -> SecretsFile {
+    let mut secrets = SecretsFile::default();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim().to_string();
+            match key.trim() {
+                "username" => secrets.username = Some(value),
+                "password" => secrets.password = Some(value),
+                "encryption_password" => secrets.encryption_password = Some(value),
+                "key_file" => secrets.key_file = Some(value),
+                "key_id" => secrets.key_id = Some(value),
+                _ => {}
+            }
+        }
+    }
+    secrets
+}
+
+/// prompts for a password without echoing it, only if stdin is a terminal
+pub fn prompt_password(prompt: &str) -> Result<String, Box<dyn Error>> {
+    if !std::io::stdin().is_terminal() {
+        return Err(format!(
+            "no password given, set {PASSWORD_VAR} or {SECRETS_FILE_VAR}, or run from a terminal"
+        )
+        .into());
+    }
+    Ok(rpassword::prompt_password(prompt)?)
+}
+
+fn prompt_line(prompt: &str) -> Result<String, Box<dyn Error>> {
+    if !std::io::stdin().is_terminal() {
+        return Err(format!("no username given, set {USERNAME_VAR} or {SECRETS_FILE_VAR}").into());
+    }
+    eprint!("{prompt}");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_secrets() {
+        let secrets = parse_secrets(
+            "# camera creds\nusername = admin\npassword=p=ss\n\nencryption_password = other\nkey_id = 2024\n",
+        );
+        assert_eq!(secrets.username.as_deref(), Some("admin"));
+        assert_eq!(secrets.password.as_deref(), Some("p=ss"));
+        assert_eq!(secrets.encryption_password.as_deref(), Some("other"));
+        assert_eq!(secrets.key_id.as_deref(), Some("2024"));
+    }
+
+    #[test]
+    fn test_world_readable_refused() {
+        let path =
+            std::env::temp_dir().join(format!("security-cam-test-secrets-{}", std::process::id()));
+        fs::write(&path, "password=pass\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        read_secrets_file(&path).unwrap_err();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        let secrets = read_secrets_file(&path).unwrap();
+        assert_eq!(secrets.password.as_deref(), Some("pass"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use security_cam_common::futures::{stream, Stream, StreamExt};
+use std::error::Error;
+use std::io::ErrorKind;
+
+use crate::keys::KeyPair;
+
+/// first bytes of a framed upload
+pub const MAGIC: &[u8; 4] = b"SCF1";
+
+const NONCE_LEN: usize = 12;
+
+/// encrypts each frame of a recording on its own, so that frames of any size can be split apart again
+/// with decrypt_frames
+///
+/// a framed upload starts with a header:
+///     MAGIC | salt length (u8) | salt
+/// where the salt is the one the key was derived from the passphrase with, followed by one record per frame:
+///     length (u32) | timestamp in ms since the unix epoch (u64) | nonce (12 bytes) | ciphertext
+/// where length counts the nonce and ciphertext. numbers are big endian.
+/// the ciphertext is AES-256-GCM over the jpeg, with the frame's index in the recording and its timestamp as
+/// associated data so records cant be reordered or retimed without it being noticed
+pub struct FrameEncryptor {
+    cipher: Aes256Gcm,
+    index: u64,
+}
+
+impl FrameEncryptor {
+    pub fn new(key: &[u8; 32]) -> Self {
+        FrameEncryptor {
+            cipher: Aes256Gcm::new(key.into()),
+            index: 0,
+        }
+    }
+
+    /// the header that goes before the first record
+    pub fn header(salt: &str) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.push(salt.len() as u8);
+        header.extend_from_slice(salt.as_bytes());
+        header
+    }
+
+    /// encrypts the next frame of the recording into a record
+    pub fn encrypt(&mut self, timestamp_ms: u64, frame: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: frame,
+                    aad: &associated_data(self.index, timestamp_ms),
+                },
+            )
+            .map_err(|_| std::io::Error::new(ErrorKind::Other, "couldnt encrypt frame"))?;
+        self.index += 1;
+
+        let mut record = Vec::with_capacity(4 + 8 + NONCE_LEN + ciphertext.len());
+        record.extend_from_slice(&((NONCE_LEN + ciphertext.len()) as u32).to_be_bytes());
+        record.extend_from_slice(&timestamp_ms.to_be_bytes());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&ciphertext);
+        Ok(record)
+    }
+}
+
+fn associated_data(index: u64, timestamp_ms: u64) -> [u8; 16] {
+    let mut aad = [0u8; 16];
+    aad[..8].copy_from_slice(&index.to_be_bytes());
+    aad[8..].copy_from_slice(&timestamp_ms.to_be_bytes());
+    aad
+}
+
+/// turns a stream of (timestamp, jpeg) frames into the bytes of a framed upload
+pub fn encrypt_frames<S, B>(
+    key_pair: KeyPair,
+    frames: S,
+) -> impl Stream<Item = Result<Vec<u8>, std::io::Error>> + Send
+where
+    S: Stream<Item = Result<(u64, B), std::io::Error>> + Send,
+    B: AsRef<[u8]>,
+{
+    let (key, salt) = key_pair;
+    let header = FrameEncryptor::header(salt.as_str());
+    let mut encryptor = FrameEncryptor::new(&key);
+    stream::once(async move { Ok(header) }).chain(frames.map(move |frame| {
+        let (timestamp_ms, frame) = frame?;
+        encryptor.encrypt(timestamp_ms, frame.as_ref())
+    }))
+}
+
+/// encrypts data as a framed upload with just one frame, for small documents like metadata and thumbnails
+pub fn encrypt_single(
+    key_pair: KeyPair,
+    timestamp_ms: u64,
+    data: &[u8],
+) -> Result<Vec<u8>, std::io::Error> {
+    let (key, salt) = key_pair;
+    let mut encrypted = FrameEncryptor::header(salt.as_str());
+    encrypted.extend(FrameEncryptor::new(&key).encrypt(timestamp_ms, data)?);
+    Ok(encrypted)
+}
+
+/// a frame taken back out of a framed upload
+#[derive(Debug, PartialEq, Eq)]
+pub struct DecryptedFrame {
+    pub timestamp_ms: u64,
+    pub frame: Vec<u8>,
+}
+
+/// splits a framed upload back into its frames. key_for_salt is handed the salt from the header and returns
+/// the key, eg. by deriving it from the passphrase with keys::derive_key
+pub fn decrypt_frames(
+    upload: &[u8],
+    key_for_salt: impl FnOnce(&str) -> Result<[u8; 32], Box<dyn Error>>,
+) -> Result<Vec<DecryptedFrame>, Box<dyn Error>> {
+    let truncated = || "framed upload is truncated";
+    if upload.get(..4) != Some(MAGIC.as_slice()) {
+        return Err("not a framed upload".into());
+    }
+    let salt_len = *upload.get(4).ok_or_else(truncated)? as usize;
+    let salt = std::str::from_utf8(upload.get(5..5 + salt_len).ok_or_else(truncated)?)?;
+    let cipher = Aes256Gcm::new((&key_for_salt(salt)?).into());
+
+    let mut frames = Vec::new();
+    let mut rest = &upload[5 + salt_len..];
+    while !rest.is_empty() {
+        let len = u32::from_be_bytes(rest.get(..4).ok_or_else(truncated)?.try_into()?) as usize;
+        let timestamp_ms = u64::from_be_bytes(rest.get(4..12).ok_or_else(truncated)?.try_into()?);
+        let body = rest.get(12..12 + len).ok_or_else(truncated)?;
+        if len < NONCE_LEN {
+            return Err(truncated().into());
+        }
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        let frame = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &associated_data(frames.len() as u64, timestamp_ms),
+                },
+            )
+            .map_err(|_| format!("frame {} failed to decrypt", frames.len()))?;
+        frames.push(DecryptedFrame {
+            timestamp_ms,
+            frame,
+        });
+        rest = &rest[12 + len..];
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{derive_key, derive_raw_key, generate_key_file, read_key_file, KeyCache};
+    use security_cam_common::encryption::generate_key;
+    use security_cam_common::shuttle_runtime::tokio;
+
+    /// frames of different sizes, like an mjpeg stream gives
+    fn frames() -> Vec<(u64, Vec<u8>)> {
+        vec![
+            (1_700_000_000_000, vec![1; 10]),
+            (1_700_000_000_040, vec![2; 70_000]),
+            (1_700_000_000_080, Vec::new()),
+            (1_700_000_000_120, vec![3; 3]),
+        ]
+    }
+
+    async fn encrypt(key_pair: KeyPair) -> Vec<u8> {
+        let input = stream::iter(frames().into_iter().map(Ok));
+        let chunks = encrypt_frames(key_pair, input).collect::<Vec<_>>().await;
+        chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_with_passphrase() {
+        // the key comes from security-cam-common, and has to be derivable again from the passphrase and the
+        // salt in the header
+        let key_pair = generate_key("correct horse").unwrap();
+        let upload = encrypt(key_pair).await;
+        let decrypted = decrypt_frames(&upload, |salt| derive_key("correct horse", salt)).unwrap();
+        let expected = frames()
+            .into_iter()
+            .map(|(timestamp_ms, frame)| DecryptedFrame {
+                timestamp_ms,
+                frame,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(decrypted, expected);
+
+        decrypt_frames(&upload, |salt| derive_key("wrong horse", salt)).unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_with_key_file() {
+        let path =
+            std::env::temp_dir().join(format!("security-cam-framing-key-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        generate_key_file(&path).unwrap();
+        let key_pair = KeyCache::from_raw_key(read_key_file(&path).unwrap())
+            .take()
+            .await
+            .unwrap();
+        let upload = encrypt(key_pair).await;
+
+        // all that is needed to decrypt is the key file and the salt in the header
+        let key = read_key_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let decrypted = decrypt_frames(&upload, |salt| derive_raw_key(&key, salt)).unwrap();
+        assert_eq!(decrypted.len(), frames().len());
+        assert_eq!(decrypted[1].frame, frames()[1].1);
+        decrypt_frames(&upload, |salt| derive_raw_key(&[0; 32], salt)).unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_tampering_detected() {
+        let key_pair = generate_key("pass").unwrap();
+        let key = key_pair.0;
+        let mut upload = encrypt(key_pair).await;
+
+        decrypt_frames(&upload[..upload.len() - 1], |_| Ok(key)).unwrap_err();
+
+        // bump the first frame's timestamp
+        let first_record = 5 + upload[4] as usize;
+        upload[first_record + 11] ^= 1;
+        decrypt_frames(&upload, |_| Ok(key)).unwrap_err();
+    }
+}
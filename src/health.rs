@@ -0,0 +1,304 @@
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Url;
+use security_cam_common::shuttle_runtime::tokio;
+use serde::Serialize;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::arming::{ArmMode, Arming};
+use crate::motiondetection::unix_millis;
+
+/// environment variable holding how many seconds apart heartbeats are sent
+pub const HEARTBEAT_SECONDS_VAR: &str = "SECURITY_CAM_HEARTBEAT_SECONDS";
+
+/// heartbeats failing this many times in a row means the server has lost track of the camera
+const FAILING_AFTER: u32 = 3;
+
+#[derive(Clone, Debug)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl HeartbeatConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let mut config = HeartbeatConfig::default();
+        if let Ok(seconds) = std::env::var(HEARTBEAT_SECONDS_VAR) {
+            let seconds: u64 = seconds
+                .trim()
+                .parse()
+                .ok()
+                .filter(|seconds| *seconds > 0)
+                .ok_or_else(|| format!("{HEARTBEAT_SECONDS_VAR} must be a positive integer"))?;
+            config.interval = Duration::from_secs(seconds);
+        }
+        Ok(config)
+    }
+}
+
+/// what the camera is doing, shared between the capture thread, the upload loop and whatever reports on them
+#[derive(Debug)]
+pub struct CameraStatus {
+    started: Instant,
+
+    /// frames captured in the last second
+    pub capture_fps: AtomicUsize,
+
+    /// capture time of the last frame, in ms since the unix epoch, 0 before the first one
+    pub last_frame_ms: AtomicU64,
+
+    /// motion score of the last frame
+    pub motion_score: AtomicU32,
+
+    /// movement has been seen recently and frames are being recorded
+    pub motion: AtomicBool,
+
+    /// what happens to motion right now
+    pub arming: Arming,
+
+    /// recordings started that havent finished uploading
+    pub uploads: AtomicUsize,
+
+    /// set once heartbeats have failed FAILING_AFTER times in a row, until one gets through
+    pub heartbeat_failing: AtomicBool,
+
+    /// (ms since the unix epoch, message)
+    last_error: Mutex<Option<(u64, String)>>,
+}
+
+impl Default for CameraStatus {
+    fn default() -> Self {
+        CameraStatus {
+            started: Instant::now(),
+            capture_fps: AtomicUsize::new(0),
+            last_frame_ms: AtomicU64::new(0),
+            motion_score: AtomicU32::new(0),
+            motion: AtomicBool::new(false),
+            arming: Arming::default(),
+            uploads: AtomicUsize::new(0),
+            heartbeat_failing: AtomicBool::new(false),
+            last_error: Mutex::new(None),
+        }
+    }
+}
+
+impl CameraStatus {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn record_error(&self, error: impl ToString) {
+        *self.last_error.lock().unwrap() = Some((unix_millis(), error.to_string()));
+    }
+
+    pub fn last_error(&self) -> Option<(u64, String)> {
+        self.last_error.lock().unwrap().clone()
+    }
+}
+
+/// the body of a heartbeat
+#[derive(Debug, Serialize)]
+pub struct HeartbeatReport {
+    pub camera_id: String,
+    pub client_version: String,
+    pub uptime_secs: u64,
+    pub capture_fps: usize,
+    pub last_frame_ms: Option<u64>,
+    pub arm_mode: ArmMode,
+
+    /// the arm mode has been set by hand instead of following the schedule
+    pub arm_override: bool,
+    pub motion: bool,
+
+    /// bytes waiting in the spool directory
+    pub spool_bytes: u64,
+    pub disk_free_bytes: Option<u64>,
+    pub last_error: Option<String>,
+    pub last_error_ms: Option<u64>,
+
+    /// heartbeats that didnt get through since the last one that did
+    pub failed_heartbeats: u32,
+}
+
+impl HeartbeatReport {
+    pub fn new(camera_id: &str, status: &CameraStatus, spool_dir: &Path, failed: u32) -> Self {
+        let last_frame_ms = status.last_frame_ms.load(Ordering::Relaxed);
+        let last_error = status.last_error();
+        HeartbeatReport {
+            camera_id: camera_id.to_string(),
+            client_version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_secs: status.uptime().as_secs(),
+            capture_fps: status.capture_fps.load(Ordering::Relaxed),
+            last_frame_ms: (last_frame_ms > 0).then_some(last_frame_ms),
+            arm_mode: status.arming.mode(),
+            arm_override: status.arming.overridden().is_some(),
+            motion: status.motion.load(Ordering::Relaxed),
+            spool_bytes: dir_size(spool_dir),
+            disk_free_bytes: disk_free(spool_dir),
+            last_error_ms: last_error.as_ref().map(|(at, _)| *at),
+            last_error: last_error.map(|(_, error)| error),
+            failed_heartbeats: failed,
+        }
+    }
+}
+
+/// posts a HeartbeatReport to `heartbeat/<camera>` every interval, so the server can tell a dead camera from a
+/// quiet one
+pub struct Heartbeat {
+    pub(crate) client: reqwest::Client,
+    pub(crate) addr: Url,
+    pub(crate) camera_id: String,
+    pub(crate) config: HeartbeatConfig,
+    pub(crate) status: Arc<CameraStatus>,
+
+    /// where recordings and spilled frames are kept
+    pub(crate) spool_dir: PathBuf,
+
+    /// heartbeats in a row that didnt get through
+    pub(crate) failed: u32,
+}
+
+impl Heartbeat {
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(self.config.interval);
+        loop {
+            interval.tick().await;
+            let result = self.send().await;
+            self.record(result);
+        }
+    }
+
+    async fn send(&self) -> Result<(), Box<dyn Error>> {
+        let report =
+            HeartbeatReport::new(&self.camera_id, &self.status, &self.spool_dir, self.failed);
+        self.client
+            .post(self.addr.join("heartbeat/")?.join(&self.camera_id)?)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&report)?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// keeps count of failed heartbeats, flagging the camera once they keep failing
+    fn record(&mut self, result: Result<(), Box<dyn Error>>) {
+        match result {
+            Ok(()) => {
+                if self.status.heartbeat_failing.swap(false, Ordering::Relaxed) {
+                    info!(
+                        "heartbeats getting through again after {} failures",
+                        self.failed
+                    );
+                }
+                self.failed = 0;
+            }
+            Err(e) => {
+                self.failed += 1;
+                warn!("sending heartbeat: {e}");
+                if self.failed >= FAILING_AFTER
+                    && !self.status.heartbeat_failing.swap(true, Ordering::Relaxed)
+                {
+                    error!(
+                        "{} heartbeats in a row have failed, the server will think this camera is down",
+                        self.failed
+                    );
+                    self.status.record_error(format!("heartbeats failing: {e}"));
+                }
+            }
+        }
+    }
+}
+
+/// total size of the files in dir, not counting subdirectories
+pub fn dir_size(dir: &Path) -> u64 {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.metadata().ok())
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// space left for unprivileged users on the filesystem holding path
+pub fn disk_free(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path is a valid nul terminated string and stat is a statvfs for it to fill in
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat() -> Heartbeat {
+        Heartbeat {
+            client: reqwest::Client::new(),
+            addr: Url::parse("http://localhost").unwrap(),
+            camera_id: "garage".to_string(),
+            config: HeartbeatConfig::default(),
+            status: Arc::new(CameraStatus::default()),
+            spool_dir: std::env::temp_dir(),
+            failed: 0,
+        }
+    }
+
+    #[test]
+    fn test_flags_failing_heartbeats() {
+        let mut heartbeat = heartbeat();
+        for _ in 0..FAILING_AFTER - 1 {
+            heartbeat.record(Err("connection refused".into()));
+        }
+        assert!(!heartbeat.status.heartbeat_failing.load(Ordering::Relaxed));
+        heartbeat.record(Err("connection refused".into()));
+        assert!(heartbeat.status.heartbeat_failing.load(Ordering::Relaxed));
+        assert!(heartbeat.status.last_error().is_some());
+
+        let report = HeartbeatReport::new(
+            "garage",
+            &heartbeat.status,
+            &heartbeat.spool_dir,
+            heartbeat.failed,
+        );
+        assert_eq!(report.failed_heartbeats, FAILING_AFTER);
+        assert_eq!(report.last_frame_ms, None);
+
+        heartbeat.record(Ok(()));
+        assert!(!heartbeat.status.heartbeat_failing.load(Ordering::Relaxed));
+        assert_eq!(heartbeat.failed, 0);
+    }
+
+    #[test]
+    fn test_spool_size() {
+        let dir =
+            std::env::temp_dir().join(format!("security-cam-spool-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("1.spill.0.jpg"), [0; 100]).unwrap();
+        std::fs::write(dir.join("1.spill.1.jpg"), [0; 50]).unwrap();
+        std::fs::write(dir.join("nested").join("ignored"), [0; 10]).unwrap();
+
+        assert_eq!(dir_size(&dir), 150);
+        assert!(disk_free(&dir).is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+use argon2::password_hash::SaltString;
+use argon2::Argon2;
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use security_cam_common::shuttle_runtime::tokio::sync::watch;
+use sha2::Sha256;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::thread;
+use tracing::error;
+
+use crate::credentials::check_permissions;
+
+/// a recording's key along with the salt it can be derived again with
+pub type KeyPair = ([u8; 32], SaltString);
+
+/// length of the salts SaltString::generate makes, a recording's salt is two of them
+const SALT_LEN: usize = 22;
+
+/// what HKDF expands recording keys with, so they cant be mistaken for keys made for anything else
+const RECORDING_KEY_INFO: &[u8] = b"security-cam recording key";
+
+/// the key recording keys are expanded from, derived once per session
+#[derive(Clone)]
+struct MasterKey {
+    key: [u8; 32],
+
+    /// the salt key was derived from the passphrase with, None for a raw key
+    salt: Option<SaltString>,
+}
+
+/// hands out a fresh key for every recording without a password hash each time.
+/// the passphrase is hashed once, on a background thread, into a master key, and each recording's key is expanded
+/// from that with HKDF and a random salt of its own. the salt sent with a recording is the master key's salt
+/// followed by the recording's, which derive_key takes apart again
+pub struct KeyCache {
+    source: KeySource,
+}
+
+enum KeySource {
+    /// None until the background thread has derived the master key
+    Passphrase(watch::Receiver<Option<Result<MasterKey, String>>>),
+
+    /// a random key loaded from a key file, used as the master key straight away
+    Raw([u8; 32]),
+}
+
+impl KeyCache {
+    /// starts deriving the master key for passphrase in the background
+    pub fn new(passphrase: &str) -> Self {
+        let (tx, rx) = watch::channel(None);
+        let passphrase = passphrase.to_string();
+        thread::spawn(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            let master = hash_passphrase(&passphrase, salt.as_str())
+                .map(|key| MasterKey {
+                    key,
+                    salt: Some(salt),
+                })
+                .map_err(|e| e.to_string());
+            if let Err(e) = &master {
+                error!("couldnt derive the master key: {e}");
+            }
+            let _ = tx.send(Some(master));
+        });
+        KeyCache {
+            source: KeySource::Passphrase(rx),
+        }
+    }
+
+    /// uses key for every recording instead of deriving one from a passphrase
+    pub fn from_raw_key(key: [u8; 32]) -> Self {
+        KeyCache {
+            source: KeySource::Raw(key),
+        }
+    }
+
+    /// waits for the master key if it is still being derived
+    async fn master(&self) -> Result<MasterKey, Box<dyn Error>> {
+        let mut master = match &self.source {
+            KeySource::Passphrase(master) => master.clone(),
+            KeySource::Raw(key) => {
+                return Ok(MasterKey {
+                    key: *key,
+                    salt: None,
+                })
+            }
+        };
+        let master = master
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| "the master key was never derived")?;
+        match &*master {
+            Some(Ok(master)) => Ok(master.clone()),
+            Some(Err(e)) => Err(format!("couldnt derive the master key: {e}").into()),
+            None => unreachable!("waited for it to be set"),
+        }
+    }
+
+    /// a new key and salt for a recording. only waits on the first call, while the master key is derived
+    pub async fn take(&self) -> Result<KeyPair, Box<dyn Error>> {
+        let master = self.master().await?;
+        let recording_salt = SaltString::generate(&mut OsRng);
+        let key = recording_key(&master.key, recording_salt.as_str())?;
+        let Some(master_salt) = master.salt else {
+            return Ok((key, recording_salt));
+        };
+        let salt = format!("{}{}", master_salt.as_str(), recording_salt.as_str());
+        let salt = SaltString::from_b64(&salt)
+            .map_err(|e| format!("couldnt make the recording's salt: {e}"))?;
+        Ok((key, salt))
+    }
+}
+
+/// derives a recording's key again from the passphrase and the salt sent with it. salts from KeyCache are the
+/// master key's salt followed by the recording's, any other salt is used with the passphrase directly, the way
+/// security-cam-common's generate_key does
+pub fn derive_key(passphrase: &str, salt: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    if salt.len() == SALT_LEN * 2 {
+        let (master_salt, recording_salt) = salt.split_at(SALT_LEN);
+        return recording_key(&hash_passphrase(passphrase, master_salt)?, recording_salt);
+    }
+    hash_passphrase(passphrase, salt)
+}
+
+/// derives a recording's key again from a key file's key and the salt sent with the recording
+pub fn derive_raw_key(key: &[u8; 32], salt: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    recording_key(key, salt)
+}
+
+fn hash_passphrase(passphrase: &str, salt: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut key)
+        .map_err(|e| format!("couldnt derive key: {e}"))?;
+    Ok(key)
+}
+
+/// expands the key for the recording with salt from master
+fn recording_key(master: &[u8; 32], salt: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(salt.as_bytes()), master)
+        .expand(RECORDING_KEY_INFO, &mut key)
+        .map_err(|e| format!("couldnt expand the recording key: {e}"))?;
+    Ok(key)
+}
+
+/// writes a new random 256 bit key to path as hex, readable only by the owner.
+/// fails if the file already exists so an existing key is never overwritten
+pub fn generate_key_file(path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{}", to_hex(&key))?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// reads a key written by generate_key_file, either as 64 hex characters or 32 raw bytes,
+/// refusing the file if other users can access it
+pub fn read_key_file(path: impl AsRef<Path>) -> Result<[u8; 32], Box<dyn Error>> {
+    let path = path.as_ref();
+    check_permissions(path)?;
+    let contents = fs::read(path)?;
+    if let Ok(key) = <[u8; 32]>::try_from(contents.as_slice()) {
+        return Ok(key);
+    }
+    let hex = std::str::from_utf8(&contents)
+        .map_err(|_| "key file must contain 32 bytes or 64 hex characters")?
+        .trim();
+    from_hex(hex).ok_or_else(|| "key file must contain 32 bytes or 64 hex characters".into())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use security_cam_common::encryption::generate_key;
+    use security_cam_common::shuttle_runtime::tokio;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_key_file_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("security-cam-test-key-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        generate_key_file(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let key = read_key_file(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), to_hex(&key));
+
+        // never overwrite an existing key
+        generate_key_file(&path).unwrap_err();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        read_key_file(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_derive_key_matches_generate_key() {
+        // recordings are decrypted with derive_key, so it has to give back the key security-cam-common derived
+        let (key, salt) = generate_key("correct horse").unwrap();
+        assert_eq!(derive_key("correct horse", salt.as_str()).unwrap(), key);
+        assert_ne!(derive_key("wrong horse", salt.as_str()).unwrap(), key);
+    }
+
+    #[tokio::test]
+    async fn test_recording_keys_from_master_key() {
+        let cache = KeyCache::new("correct horse");
+        let (key1, salt1) = cache.take().await.unwrap();
+        let (key2, salt2) = cache.take().await.unwrap();
+        assert_ne!(key1, key2);
+        // both come from the same master key, but each recording can be decrypted on its own
+        assert_eq!(salt1.as_str()[..SALT_LEN], salt2.as_str()[..SALT_LEN]);
+        assert_eq!(derive_key("correct horse", salt1.as_str()).unwrap(), key1);
+        assert_eq!(derive_key("correct horse", salt2.as_str()).unwrap(), key2);
+        assert_ne!(derive_key("wrong horse", salt1.as_str()).unwrap(), key1);
+    }
+
+    #[tokio::test]
+    async fn test_raw_key_gets_fresh_salt() {
+        let cache = KeyCache::from_raw_key([7; 32]);
+        let (key1, salt1) = cache.take().await.unwrap();
+        let (key2, salt2) = cache.take().await.unwrap();
+        assert_ne!(salt1.as_str(), salt2.as_str());
+        assert_ne!(key1, key2);
+        assert_ne!(key1, [7; 32]);
+        assert_eq!(derive_raw_key(&[7; 32], salt1.as_str()).unwrap(), key1);
+        assert_eq!(derive_raw_key(&[7; 32], salt2.as_str()).unwrap(), key2);
+    }
+}
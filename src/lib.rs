@@ -1,2 +1,28 @@
+pub mod adaptive;
+pub mod arming;
+pub mod bandwidth;
+pub mod chunked;
+pub mod config;
+pub mod control;
+pub mod credentials;
+pub mod framing;
+pub mod health;
+pub mod keys;
+pub mod liveview;
+pub mod local_api;
+pub mod logging;
+pub mod metadata;
+pub mod metrics;
+#[cfg(test)]
+mod mock_server;
 pub mod motiondetection;
 pub mod networking;
+pub mod preview;
+pub mod proxy;
+pub mod recording;
+pub mod remote_config;
+pub mod schedule;
+pub mod shutdown;
+pub mod thumbnail;
+pub mod tls;
+pub mod upload_queue;
@@ -0,0 +1,180 @@
+use reqwest::Url;
+use reqwest_websocket::{Message, RequestBuilderExt};
+use security_cam_common::futures::{SinkExt, StreamExt};
+use security_cam_common::shuttle_runtime::tokio;
+use security_cam_common::shuttle_runtime::tokio::sync::{oneshot, watch};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::framing::FrameEncryptor;
+use crate::keys::KeyCache;
+use crate::motiondetection::LiveFrame;
+use crate::networking::KEY_ID_HEADER;
+
+/// environment variable holding the most frames per second live view sends
+pub const LIVE_MAX_FPS_VAR: &str = "SECURITY_CAM_LIVE_MAX_FPS";
+
+/// environment variable holding how many seconds live view runs before stopping on its own
+pub const LIVE_MAX_SECONDS_VAR: &str = "SECURITY_CAM_LIVE_MAX_SECONDS";
+
+#[derive(Clone, Debug)]
+pub struct LiveViewConfig {
+    pub max_fps: u32,
+
+    /// a live view the server forgets to stop is torn down after this long
+    pub max_duration: Duration,
+}
+
+impl Default for LiveViewConfig {
+    fn default() -> Self {
+        LiveViewConfig {
+            max_fps: 5,
+            max_duration: Duration::from_secs(300),
+        }
+    }
+}
+
+impl LiveViewConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let mut config = LiveViewConfig::default();
+        if let Ok(max_fps) = std::env::var(LIVE_MAX_FPS_VAR) {
+            config.max_fps = max_fps
+                .trim()
+                .parse()
+                .ok()
+                .filter(|max_fps| *max_fps > 0)
+                .ok_or_else(|| format!("{LIVE_MAX_FPS_VAR} must be a positive integer"))?;
+        }
+        if let Ok(max_seconds) = std::env::var(LIVE_MAX_SECONDS_VAR) {
+            let max_seconds: u64 = max_seconds
+                .trim()
+                .parse()
+                .ok()
+                .filter(|max_seconds| *max_seconds > 0)
+                .ok_or_else(|| format!("{LIVE_MAX_SECONDS_VAR} must be a positive integer"))?;
+            config.max_duration = Duration::from_secs(max_seconds);
+        }
+        Ok(config)
+    }
+}
+
+/// streams the camera to the server on demand, independent of motion.
+///
+/// started and stopped from the control channel (see control). on start, a websocket is opened to
+/// `live_stream/<camera>` and every frame is sent as a binary message until stop is called, the server closes the
+/// stream, the LiveView is dropped or max_duration runs out. the first message is a framed
+/// upload header (see framing) and each message after it one encrypted frame record, so the messages put together
+/// are a framed upload
+pub struct LiveView {
+    pub(crate) client: reqwest::Client,
+    pub(crate) addr: Url,
+    pub(crate) camera_id: String,
+    pub(crate) key_id: String,
+    pub(crate) keys: Arc<KeyCache>,
+    pub(crate) config: LiveViewConfig,
+    pub(crate) frames: watch::Receiver<Option<LiveFrame>>,
+
+    /// dropping the sender stops the stream, so a LiveView going away never leaves one running
+    pub(crate) session: Option<oneshot::Sender<()>>,
+}
+
+impl LiveView {
+    /// starts streaming at fps, or LiveViewConfig::max_fps if that is lower or fps is None.
+    /// does nothing if a stream is already running
+    pub fn start(&mut self, fps: Option<u32>) {
+        if self.session.as_ref().is_some_and(|stop| !stop.is_closed()) {
+            return;
+        }
+        let (stop_tx, stop_rx) = oneshot::channel();
+        self.session = Some(stop_tx);
+        let fps = fps.map_or(self.config.max_fps, |fps| {
+            fps.min(self.config.max_fps).max(1)
+        });
+        let stream = self.stream(fps, stop_rx);
+        let camera_id = self.camera_id.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = stream.await {
+                error!("live view of camera {camera_id}: {e}");
+            }
+        });
+    }
+
+    /// the last frame captured, motion or not
+    pub fn latest_frame(&self) -> Option<LiveFrame> {
+        self.frames.borrow().clone()
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(stop) = self.session.take() {
+            let _ = stop.send(());
+        }
+    }
+
+    /// sends frames at up to fps frames per second until stop fires or is dropped
+    fn stream(
+        &self,
+        fps: u32,
+        mut stop: oneshot::Receiver<()>,
+    ) -> impl std::future::Future<Output = Result<(), Box<dyn Error>>> {
+        let client = self.client.clone();
+        let url = self
+            .addr
+            .join("live_stream/")
+            .and_then(|url| url.join(&self.camera_id));
+        let key_id = self.key_id.clone();
+        let keys = self.keys.clone();
+        let max_duration = self.config.max_duration;
+        let mut frames = self.frames.clone();
+        async move {
+            let stream = client
+                .get(url?)
+                .header(KEY_ID_HEADER, key_id)
+                .upgrade()
+                .send()
+                .await?
+                .into_websocket()
+                .await?;
+            let (mut sink, mut incoming) = stream.split();
+            let (key, salt) = keys.take().await?;
+            let mut encryptor = FrameEncryptor::new(&key);
+            sink.send(Message::Binary(FrameEncryptor::header(salt.as_str())))
+                .await?;
+            info!(fps, "live view started");
+
+            let frame_interval = Duration::from_secs(1) / fps;
+            let deadline = tokio::time::sleep(max_duration);
+            tokio::pin!(deadline);
+            frames.mark_unchanged();
+            loop {
+                tokio::select! {
+                    _ = &mut stop => break,
+                    _ = &mut deadline => {
+                        info!("live view stopped after {max_duration:?}");
+                        break;
+                    }
+                    message = incoming.next() => match message {
+                        None | Some(Ok(Message::Close { .. })) => break,
+                        Some(Err(e)) => return Err(e.into()),
+                        Some(Ok(_)) => {}
+                    },
+                    changed = frames.changed() => {
+                        // the capture thread has stopped
+                        changed?;
+                        let frame = frames.borrow_and_update().clone();
+                        if let Some(frame) = frame {
+                            let record = encryptor.encrypt(frame.timestamp_ms, &frame.jpeg)?;
+                            sink.send(Message::Binary(record)).await?;
+                        }
+                        // anything captured in the meantime is skipped, only the latest frame is kept
+                        tokio::time::sleep(frame_interval).await;
+                    }
+                }
+            }
+            sink.close().await?;
+            info!("live view stopped");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,436 @@
+use actix_web::http::header::{AUTHORIZATION, CACHE_CONTROL, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use security_cam_common::futures::stream;
+use security_cam_common::shuttle_runtime::tokio;
+use security_cam_common::shuttle_runtime::tokio::sync::watch;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::arming::ArmMode;
+use crate::health::CameraStatus;
+use crate::metrics::METRICS;
+use crate::motiondetection::{LiveFrame, PreviewFrame};
+use crate::preview::{mjpeg_part, render_preview, BOUNDARY};
+use crate::remote_config::RemoteConfig;
+
+/// environment variable holding the address the local api listens on, eg. `127.0.0.1:8081`. off if it isnt set
+pub const API_BIND_VAR: &str = "SECURITY_CAM_API_BIND";
+
+/// environment variable holding the bearer token every local api request has to carry
+pub const API_TOKEN_VAR: &str = "SECURITY_CAM_API_TOKEN";
+
+/// environment variable holding the most frames per second the preview stream sends
+pub const PREVIEW_MAX_FPS_VAR: &str = "SECURITY_CAM_PREVIEW_MAX_FPS";
+
+#[derive(Clone, Debug)]
+pub struct LocalApiConfig {
+    /// None leaves the local api off
+    pub bind: Option<SocketAddr>,
+    pub token: Option<String>,
+
+    /// every preview frame is decoded, drawn on and encoded again, so it is kept slow
+    pub preview_max_fps: u32,
+}
+
+impl Default for LocalApiConfig {
+    fn default() -> Self {
+        LocalApiConfig {
+            bind: None,
+            token: None,
+            preview_max_fps: 5,
+        }
+    }
+}
+
+impl LocalApiConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let bind = match std::env::var(API_BIND_VAR) {
+            Ok(bind) if !bind.trim().is_empty() => Some(bind.trim().parse().map_err(|_| {
+                format!("{API_BIND_VAR} must be an address and port, eg. 127.0.0.1:8081")
+            })?),
+            _ => None,
+        };
+        let token = std::env::var(API_TOKEN_VAR)
+            .ok()
+            .filter(|token| !token.is_empty());
+        // the api can arm and disarm the camera, so it is never served without authentication
+        if bind.is_some() && token.is_none() {
+            return Err(format!("{API_TOKEN_VAR} must be set to use {API_BIND_VAR}").into());
+        }
+        let mut config = LocalApiConfig {
+            bind,
+            token,
+            ..Default::default()
+        };
+        if let Ok(max_fps) = std::env::var(PREVIEW_MAX_FPS_VAR) {
+            config.preview_max_fps = max_fps
+                .trim()
+                .parse()
+                .ok()
+                .filter(|max_fps| *max_fps > 0)
+                .ok_or_else(|| format!("{PREVIEW_MAX_FPS_VAR} must be a positive integer"))?;
+        }
+        Ok(config)
+    }
+}
+
+/// the body of GET /status
+#[derive(Debug, Serialize)]
+pub struct StatusReport {
+    pub uptime_secs: u64,
+    pub capture_fps: usize,
+    pub last_frame_ms: Option<u64>,
+    pub motion: bool,
+    pub motion_score: u32,
+    pub arm_mode: ArmMode,
+    pub arm_override: bool,
+
+    /// recordings started that havent finished uploading
+    pub uploads_in_flight: usize,
+    pub last_error: Option<String>,
+}
+
+impl StatusReport {
+    pub fn new(status: &CameraStatus) -> Self {
+        let last_frame_ms = status.last_frame_ms.load(Ordering::Relaxed);
+        StatusReport {
+            uptime_secs: status.uptime().as_secs(),
+            capture_fps: status.capture_fps.load(Ordering::Relaxed),
+            last_frame_ms: (last_frame_ms > 0).then_some(last_frame_ms),
+            motion: status.motion.load(Ordering::Relaxed),
+            motion_score: status.motion_score.load(Ordering::Relaxed),
+            arm_mode: status.arming.mode(),
+            arm_override: status.arming.overridden().is_some(),
+            uploads_in_flight: status.uploads.load(Ordering::Relaxed),
+            last_error: status.last_error().map(|(_, error)| error),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ArmQuery {
+    mode: Option<ArmMode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// a small http api for looking at and controlling the camera from the local network, without going through
+/// the server. every request needs an `Authorization: Bearer <token>` header, or a `token` query parameter for
+/// browsers, which cant send headers for an `<img>`
+///
+/// - `GET /status` a StatusReport as json
+/// - `GET /snapshot` the latest frame as a jpeg
+/// - `GET /preview` an mjpeg stream of the camera with what motion detection sees drawn on top, see preview
+/// - `GET /metrics` the camera's metrics for prometheus, see metrics
+/// - `POST /arm`, optionally `?mode=<upload|local|ignore>`, `POST /disarm` and `POST /resume_schedule` work like
+///   the control channel commands of the same names
+/// - `POST /reload_config` fetches the camera's configuration from the server
+#[derive(Clone)]
+pub struct LocalApi {
+    pub(crate) token: String,
+    pub(crate) status: Arc<CameraStatus>,
+    pub(crate) frames: watch::Receiver<Option<LiveFrame>>,
+
+    /// subscribed to for as long as a preview is being watched, see MotionDetector::preview_handle
+    pub(crate) preview: watch::Sender<Option<PreviewFrame>>,
+    pub(crate) preview_max_fps: u32,
+    pub(crate) remote_config: RemoteConfig,
+
+    /// where recordings and spilled frames are kept, reported in the metrics
+    pub(crate) spool_dir: PathBuf,
+}
+
+impl LocalApi {
+    /// None if the config has no token, as the api is never served without one
+    pub fn new(
+        config: &LocalApiConfig,
+        frames: watch::Receiver<Option<LiveFrame>>,
+        preview: watch::Sender<Option<PreviewFrame>>,
+        remote_config: RemoteConfig,
+        spool_dir: PathBuf,
+    ) -> Option<Self> {
+        Some(LocalApi {
+            token: config.token.clone()?,
+            status: remote_config.status.clone(),
+            frames,
+            preview,
+            preview_max_fps: config.preview_max_fps,
+            remote_config,
+            spool_dir,
+        })
+    }
+
+    /// serves the api on bind until the server is stopped
+    pub async fn run(self, bind: SocketAddr) -> Result<(), std::io::Error> {
+        info!(%bind, "local api listening");
+        HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(self.clone()))
+                .configure(routes)
+        })
+        .workers(1)
+        // the client decides when to stop, and keeps serving status while it finishes its recordings
+        .disable_signals()
+        .bind(bind)?
+        .run()
+        .await
+    }
+
+    /// checks the bearer token, in constant time so it cant be guessed a byte at a time
+    fn authorize(&self, request: &HttpRequest) -> Result<(), HttpResponse> {
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string)
+            .or_else(|| {
+                web::Query::<TokenQuery>::from_query(request.query_string())
+                    .ok()?
+                    .into_inner()
+                    .token
+            })
+            .unwrap_or_default();
+        if constant_time_eq(token.as_bytes(), self.token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(HttpResponse::Unauthorized()
+                .insert_header((WWW_AUTHENTICATE, "Bearer"))
+                .finish())
+        }
+    }
+
+    fn set_override(&self, request: &HttpRequest, mode: Option<ArmMode>) -> HttpResponse {
+        if let Err(response) = self.authorize(request) {
+            return response;
+        }
+        self.status.arming.set_override(mode);
+        HttpResponse::Ok().json(StatusReport::new(&self.status))
+    }
+}
+
+pub(crate) fn routes(config: &mut web::ServiceConfig) {
+    config
+        .route("/status", web::get().to(status))
+        .route("/snapshot", web::get().to(snapshot))
+        .route("/preview", web::get().to(preview))
+        .route("/metrics", web::get().to(metrics))
+        .route("/arm", web::post().to(arm))
+        .route("/disarm", web::post().to(disarm))
+        .route("/resume_schedule", web::post().to(resume_schedule))
+        .route("/reload_config", web::post().to(reload_config));
+}
+
+async fn status(api: web::Data<LocalApi>, request: HttpRequest) -> HttpResponse {
+    if let Err(response) = api.authorize(&request) {
+        return response;
+    }
+    HttpResponse::Ok().json(StatusReport::new(&api.status))
+}
+
+async fn snapshot(api: web::Data<LocalApi>, request: HttpRequest) -> HttpResponse {
+    if let Err(response) = api.authorize(&request) {
+        return response;
+    }
+    let frame = api.frames.borrow().clone();
+    match frame {
+        Some(frame) => HttpResponse::Ok()
+            .content_type("image/jpeg")
+            .insert_header((CACHE_CONTROL, "no-store"))
+            .body(frame.jpeg),
+        None => HttpResponse::ServiceUnavailable().body("no frame has been captured yet"),
+    }
+}
+
+/// streams preview frames until the client goes away, at up to preview_max_fps. masks, zones and min_score are
+/// taken from the detection settings in use, so changes show up straight away
+async fn preview(api: web::Data<LocalApi>, request: HttpRequest) -> HttpResponse {
+    if let Err(response) = api.authorize(&request) {
+        return response;
+    }
+    let frames = api.preview.subscribe();
+    let detection = api.remote_config.detection.clone();
+    let frame_interval = Duration::from_secs(1) / api.preview_max_fps;
+    let parts = stream::unfold((frames, None), move |(mut frames, next)| {
+        let detection = detection.clone();
+        async move {
+            if let Some(next) = next {
+                tokio::time::sleep_until(next).await;
+            }
+            loop {
+                // the capture thread has stopped
+                frames.changed().await.ok()?;
+                let Some(frame) = frames.borrow_and_update().clone() else {
+                    continue;
+                };
+                let config = detection.borrow().clone();
+                match web::block(move || render_preview(&frame, &config)).await {
+                    Ok(Ok(jpeg)) => {
+                        let next = tokio::time::Instant::now() + frame_interval;
+                        return Some((
+                            Ok::<_, actix_web::Error>(mjpeg_part(&jpeg)),
+                            (frames, Some(next)),
+                        ));
+                    }
+                    Ok(Err(e)) => warn!("drawing preview frame: {e}"),
+                    Err(e) => warn!("drawing preview frame: {e}"),
+                }
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type(format!("multipart/x-mixed-replace; boundary={BOUNDARY}"))
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .streaming(parts)
+}
+
+async fn metrics(api: web::Data<LocalApi>, request: HttpRequest) -> HttpResponse {
+    if let Err(response) = api.authorize(&request) {
+        return response;
+    }
+    let api = api.into_inner();
+    // reading the spool directory touches the disk
+    match web::block(move || METRICS.render(&api.status, &api.spool_dir)).await {
+        Ok(text) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(text),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+async fn arm(
+    api: web::Data<LocalApi>,
+    request: HttpRequest,
+    query: web::Query<ArmQuery>,
+) -> HttpResponse {
+    api.set_override(&request, Some(query.mode.unwrap_or_default()))
+}
+
+async fn disarm(api: web::Data<LocalApi>, request: HttpRequest) -> HttpResponse {
+    api.set_override(&request, Some(ArmMode::Ignore))
+}
+
+async fn resume_schedule(api: web::Data<LocalApi>, request: HttpRequest) -> HttpResponse {
+    api.set_override(&request, None)
+}
+
+async fn reload_config(api: web::Data<LocalApi>, request: HttpRequest) -> HttpResponse {
+    if let Err(response) = api.authorize(&request) {
+        return response;
+    }
+    match api.remote_config.reload().await {
+        Ok(applied) => HttpResponse::Ok().json(serde_json::json!({ "applied": applied })),
+        Err(e) => HttpResponse::build(StatusCode::BAD_GATEWAY)
+            .body(format!("fetching camera configuration: {e}")),
+    }
+}
+
+/// compares the token without giving away how much of it matched through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::motiondetection::DetectionConfig;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use reqwest::Url;
+    use std::sync::atomic::AtomicU64;
+    use std::time::Duration;
+
+    fn api(frames: watch::Receiver<Option<LiveFrame>>) -> LocalApi {
+        let remote_config = RemoteConfig {
+            client: reqwest::Client::new(),
+            addr: Url::parse("http://localhost").unwrap(),
+            camera_id: "garage".to_string(),
+            secret: None,
+            poll_interval: Duration::from_secs(300),
+            detection: watch::Sender::new(DetectionConfig::default()),
+            status: Arc::new(CameraStatus::default()),
+            version: Arc::new(AtomicU64::new(0)),
+            version_file: None,
+        };
+        let config = LocalApiConfig {
+            token: Some("token".to_string()),
+            ..Default::default()
+        };
+        LocalApi::new(
+            &config,
+            frames,
+            watch::Sender::new(None),
+            remote_config,
+            std::env::temp_dir(),
+        )
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_requires_token() {
+        let (_frames_tx, frames) = watch::channel(None);
+        let api = api(frames);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(api.clone()))
+                .configure(routes),
+        )
+        .await;
+
+        let response = call_service(&app, TestRequest::post().uri("/disarm").to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let wrong = TestRequest::post()
+            .uri("/disarm")
+            .insert_header((AUTHORIZATION, "Bearer tokem"))
+            .to_request();
+        assert_eq!(
+            call_service(&app, wrong).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(api.status.arming.overridden(), None);
+
+        let arm = TestRequest::post()
+            .uri("/arm?mode=local")
+            .insert_header((AUTHORIZATION, "Bearer token"))
+            .to_request();
+        assert_eq!(call_service(&app, arm).await.status(), StatusCode::OK);
+        assert_eq!(api.status.arming.overridden(), Some(ArmMode::Local));
+    }
+
+    #[actix_web::test]
+    async fn test_snapshot() {
+        let (frames_tx, frames) = watch::channel(None);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(api(frames)))
+                .configure(routes),
+        )
+        .await;
+        let request = || {
+            TestRequest::get()
+                .uri("/snapshot")
+                .insert_header((AUTHORIZATION, "Bearer token"))
+                .to_request()
+        };
+
+        let response = call_service(&app, request()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        frames_tx.send_replace(Some(LiveFrame {
+            timestamp_ms: 1,
+            jpeg: vec![0xff, 0xd8, 0xff, 0xd9],
+            motion_score: 0,
+        }));
+        let response = call_service(&app, request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_body(response).await, vec![0xff, 0xd8, 0xff, 0xd9]);
+    }
+}
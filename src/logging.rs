@@ -0,0 +1,117 @@
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing_subscriber::EnvFilter;
+
+/// environment variable holding which messages are logged, as `tracing_subscriber` filter directives,
+/// eg. `info` or `warn,security_cam_client::motiondetection=debug`
+pub const LOG_VAR: &str = "SECURITY_CAM_LOG";
+
+/// environment variable holding the log format, `text` or `json`
+pub const LOG_FORMAT_VAR: &str = "SECURITY_CAM_LOG_FORMAT";
+
+#[derive(Clone, Debug)]
+pub struct LoggingConfig {
+    pub filter: String,
+
+    /// one json object per line instead of text, for log collectors
+    pub json: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            filter: "info".to_string(),
+            json: false,
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let mut config = LoggingConfig::default();
+        if let Ok(filter) = std::env::var(LOG_VAR) {
+            if !filter.trim().is_empty() {
+                config.filter = filter.trim().to_string();
+            }
+        }
+        if let Ok(format) = std::env::var(LOG_FORMAT_VAR) {
+            config.json = match format.trim().to_ascii_lowercase().as_str() {
+                "text" | "" => false,
+                "json" => true,
+                other => {
+                    return Err(
+                        format!("{LOG_FORMAT_VAR} must be text or json, not {other}").into(),
+                    )
+                }
+            };
+        }
+        Ok(config)
+    }
+}
+
+/// sends log messages to stderr, filtered and formatted as config says. can only be called once
+pub fn init(config: &LoggingConfig) -> Result<(), Box<dyn Error>> {
+    let filter = EnvFilter::try_new(&config.filter)
+        .map_err(|e| format!("{LOG_VAR} {}: {e}", config.filter))?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    let result = if config.json {
+        builder.json().try_init()
+    } else {
+        builder.try_init()
+    };
+    result.map_err(|e| e as Box<dyn Error>)
+}
+
+/// lets a message through at most once per interval, counting the ones held back in between.
+/// for messages that could otherwise come on every frame
+#[derive(Debug)]
+pub struct RateLimit {
+    interval: Duration,
+
+    /// when a message was last let through, and how many have been held back since
+    state: Mutex<(Option<Instant>, u64)>,
+}
+
+impl RateLimit {
+    pub const fn new(interval: Duration) -> Self {
+        RateLimit {
+            interval,
+            state: Mutex::new((None, 0)),
+        }
+    }
+
+    /// Some with the number of messages held back since the last one if this one should be logged
+    pub fn check(&self) -> Option<u64> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        let (last, suppressed) = &mut *state;
+        if last.is_some_and(|last| now.duration_since(last) < self.interval) {
+            *suppressed += 1;
+            return None;
+        }
+        *last = Some(now);
+        Some(std::mem::take(suppressed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimit::new(Duration::from_secs(10));
+        let start = Instant::now();
+        assert_eq!(limit.check_at(start), Some(0));
+        assert_eq!(limit.check_at(start + Duration::from_secs(1)), None);
+        assert_eq!(limit.check_at(start + Duration::from_secs(9)), None);
+        assert_eq!(limit.check_at(start + Duration::from_secs(10)), Some(2));
+        assert_eq!(limit.check_at(start + Duration::from_secs(11)), None);
+    }
+}
@@ -1,91 +1,412 @@
-// TODO
-
+use actix_web::rt::task::JoinHandle;
+use security_cam_client::arming::ArmMode;
+use security_cam_client::config::ClientConfig;
+use security_cam_client::credentials::{Credentials, EncryptionSecret};
+use security_cam_client::health::CameraStatus;
+use security_cam_client::keys::{generate_key_file, read_key_file};
+use security_cam_client::local_api::LocalApi;
+use security_cam_client::logging::{self, LoggingConfig, RateLimit};
 use security_cam_client::motiondetection::{FrameCommand, MotionDetector};
 use security_cam_client::networking::Client;
+use security_cam_client::recording::{LocalRecording, Recording};
+use security_cam_client::shutdown::{self, Outcome};
+use security_cam_client::upload_queue::QueueSpool;
 use security_cam_common::shuttle_runtime::tokio;
+use security_cam_common::shuttle_runtime::tokio::time::{sleep_until, timeout_at, Instant};
 use std::error::Error;
-use std::fs::{create_dir, DirEntry};
-use std::future::Future;
+use std::fs::create_dir;
 use std::path::Path;
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+/// errors that can come on every frame are logged at most this often
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(10);
 
 #[actix_web::main]
-async fn main() {
-    // username, passcode, and address should be read in from the command line and then a new Client can be constructed from them
-    set_up_dirs().expect("couldnt create video_frames directory");
+async fn main() -> ExitCode {
+    // logging isnt set up until these succeed, so their errors go straight to stderr
+    let logging = match LoggingConfig::from_env() {
+        Ok(logging) => logging,
+        Err(e) => {
+            eprintln!("[ERROR] invalid logging configuration: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = logging::init(&logging) {
+        eprintln!("[ERROR] couldnt set up logging: {e}");
+        return ExitCode::FAILURE;
+    }
+    // address and video device are read in from the command line, the passcode comes from the
+    // environment, a secrets file, or a prompt so that it never shows up in ps or shell history
+    set_up_dirs().expect("couldnt create video_frames and recordings directories");
     let args = std::env::args().collect::<Vec<_>>();
-    if args.len() != 5 {
-        println!("Usage: client <username> <passcode> <address> <video device>");
-        return;
+    if args.len() == 3 && args[1] == "generate-key" {
+        return match generate_key_file(&args[2]) {
+            Ok(_) => {
+                info!("wrote a new key to {}", args[2]);
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                error!("couldnt write key file: {e}");
+                ExitCode::FAILURE
+            }
+        };
     }
+    let (username, address, video_device) = match args.len() {
+        3 => (None, &args[1], &args[2]),
+        4 => (Some(args[1].as_str()), &args[2], &args[3]),
+        _ => {
+            eprintln!("Usage: client [username] <address> <video device>");
+            eprintln!("       client generate-key <key file>");
+            return ExitCode::FAILURE;
+        }
+    };
+    let address = address.trim();
+    let video_device: &u32 = &video_device.parse().expect("video device must be an integer");
+    let credentials = match Credentials::load(username) {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            error!("couldnt load credentials: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut config = match ClientConfig::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("invalid configuration: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if config.camera_id.is_empty() {
+        config.camera_id = video_device.to_string();
+    }
+    let camera = info_span!("camera", camera = %config.camera_id);
+    run(address, *video_device, credentials, config)
+        .instrument(camera)
+        .await
+}
 
-    let username = &args[1];
-    let passcode = &args[2];
-    let address = &args[3].trim();
-    let video_device: &u32 = &args[4].parse().expect("video device must be an integer");
-    println!("{address}");
-    let mut client = Client::new(address, username, passcode).await;
+/// logs in and records motion from video_device until capture stops or the client is asked to stop
+async fn run(
+    address: &str,
+    video_device: u32,
+    credentials: Credentials,
+    config: ClientConfig,
+) -> ExitCode {
+    info!(address, "starting");
+    let mut client = match Client::with_config(
+        address,
+        &credentials.username,
+        &credentials.password,
+        &config,
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            error!("couldnt set up the http client: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match &credentials.encryption {
+        EncryptionSecret::Passphrase(encryption_password) => {
+            client.set_encryption_password(encryption_password, &credentials.key_id)
+        }
+        EncryptionSecret::KeyFile(path) => match read_key_file(path) {
+            Ok(key) => client.set_encryption_key(key, &credentials.key_id),
+            Err(e) => {
+                error!("couldnt read key file {}: {e}", path.display());
+                return ExitCode::FAILURE;
+            }
+        },
+    }
+    client.prepare_keys();
     client.login().await.expect("failed to login");
-    let mut motion_detector = MotionDetector::new(*video_device);
+    let mut motion_detector = MotionDetector::with_config(video_device, config.detection.clone());
+    let detection = motion_detector.config_handle();
+    client.follow_detection_config(detection.subscribe());
+    // motion is uploaded, kept locally or ignored going by the arm schedule, which the server can replace or
+    // override over the control channel
+    let status = motion_detector.status();
+    status.arming.set_schedule(config.arming.clone());
+    // settings from the server are in place before capture starts, and followed from then on
+    let remote_config = client.remote_config(detection, status.clone(), "config_version".into());
+    if let Err(e) = remote_config.reload().await {
+        warn!("fetching camera configuration, starting with the local one: {e}");
+    }
+    actix_web::rt::spawn(remote_config.clone().run().in_current_span());
+    // from_env only allows a bind address with a token, so the api is never served unauthenticated
+    if let Some(bind) = config.local_api.bind {
+        if let Some(api) = LocalApi::new(
+            &config.local_api,
+            motion_detector.subscribe_live(),
+            motion_detector.preview_handle(),
+            remote_config.clone(),
+            "video_frames".into(),
+        ) {
+            actix_web::rt::spawn(
+                async move {
+                    if let Err(e) = api.run(bind).await {
+                        error!("local api on {bind}: {e}");
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+    }
+    let controller = client.controller(motion_detector.subscribe_live(), remote_config);
+    actix_web::rt::spawn(controller.run().in_current_span());
+    actix_web::rt::spawn(
+        client
+            .heartbeat(status.clone(), "video_frames".into())
+            .run()
+            .in_current_span(),
+    );
 
     // start detection loop
     motion_detector
         .start_detection()
         .expect("failed to start detection");
-    while let Some(command) = motion_detector.ask_for_filename() {
-        match command {
-            FrameCommand::Error(e) => {
-                eprintln!("[ERROR] error in camera capture stream: {}", e);
-            }
-            FrameCommand::FrameRange(video_num, last_frame_num, fps) => {
-                println!("{last_frame_num} = last frame num");
-                match client
-                    .send_frame_range(video_num, last_frame_num as usize + 1, fps)
-                    .await
-                {
-                    Ok(_) => {
-                        println!("sent frame range");
+    // a camera that has gone away fails on every frame, so errors like that are only logged now and then
+    let capture_errors = RateLimit::new(ERROR_LOG_INTERVAL);
+    let frame_errors = RateLimit::new(ERROR_LOG_INTERVAL);
+    let mut recording: Option<Recording> = None;
+    let mut local_recording: Option<LocalRecording> = None;
+    // finished recordings still uploading, waited for on shutdown
+    let mut uploads: Vec<Upload> = Vec::new();
+    // a stop signal ends capture, which ends the recording in progress, and then there is until the deadline for
+    // the uploads to finish. a second one stops without waiting
+    let stop = shutdown::stop_signal();
+    tokio::pin!(stop);
+    let mut listening = true;
+    let mut deadline: Option<Instant> = None;
+    let mut forced = false;
+    loop {
+        let command = tokio::select! {
+            command = motion_detector.next_command() => command,
+            signal = &mut stop, if listening => {
+                match signal {
+                    Ok(signal) if deadline.is_some() => {
+                        warn!(signal, "stopping without waiting for uploads");
+                        forced = true;
+                        break;
+                    }
+                    Ok(signal) => {
+                        info!(signal, "stopping, finishing recordings");
+                        deadline = Some(Instant::now() + config.shutdown.deadline);
+                        motion_detector.stop();
+                        stop.set(shutdown::stop_signal());
                     }
                     Err(e) => {
-                        eprintln!("[ERROR]: {e:?}"); // Propagate the error
+                        error!("couldnt listen for stop signals: {e}");
+                        listening = false;
                     }
                 }
+                continue;
+            }
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                warn!("capture didnt stop before the deadline");
+                break;
+            }
+        };
+        let Some(command) = command else {
+            break;
+        };
+        match command {
+            FrameCommand::Error(e) => {
+                if let Some(suppressed) = capture_errors.check() {
+                    error!(suppressed, "error in camera capture stream: {}", e);
+                }
+                status.record_error(format!("capture: {e}"));
+            }
+            // capture only sends single frames, nothing writes the files a frame range would point at
+            FrameCommand::FrameRange(video_num, ..) => {
+                warn!(video = video_num, "ignoring frame range");
             }
             FrameCommand::SingleFrame(frame) => {
                 let is_last_frame = frame.end;
-                match client.send_frame_framereader(frame).await {
-                    Ok(_) => {
-                        println!("sent frame");
+                let video_num = frame.video_num;
+                // the arm mode is decided when a recording starts, one that is already going is finished the
+                // same way
+                if let Some(recording) = recording.as_mut() {
+                    if let Err(e) = recording.push_frame(frame).await {
+                        if let Some(suppressed) = frame_errors.check() {
+                            warn!(
+                                video = video_num,
+                                suppressed, "queueing frame for upload: {e}"
+                            );
+                        }
                     }
-                    Err(e) => {
-                        eprintln!("[ERROR]: {e:?}"); // Propagate the error
+                } else if let Some(local) = local_recording.as_mut() {
+                    if let Err(e) = local.push_frame(frame).await {
+                        if let Some(suppressed) = frame_errors.check() {
+                            warn!(
+                                video = video_num,
+                                suppressed, "writing frame to local recording: {e}"
+                            );
+                        }
+                    }
+                } else {
+                    match status.arming.mode() {
+                        ArmMode::Ignore => continue,
+                        ArmMode::Upload => match client.start_recording(frame).await {
+                            Ok(new_recording) => {
+                                status.uploads.fetch_add(1, Ordering::Relaxed);
+                                recording = Some(new_recording);
+                            }
+                            Err(e) => {
+                                error!(video = video_num, "couldnt start recording: {e}");
+                                status.record_error(format!("starting recording: {e}"));
+                            }
+                        },
+                        ArmMode::Local => {
+                            match client
+                                .start_local_recording(frame, Path::new("recordings"))
+                                .await
+                            {
+                                Ok(new_recording) => local_recording = Some(new_recording),
+                                Err(e) => {
+                                    error!(video = video_num, "couldnt start local recording: {e}");
+                                    status.record_error(format!("starting local recording: {e}"));
+                                }
+                            }
+                        }
                     }
                 }
                 if is_last_frame {
-                    println!("Processing last frame");
-                    if let Some(tx) = client.tx.take() {
-                        drop(tx);
+                    if let Some(local) = local_recording.take() {
+                        finish_local_recording(local, &status).await;
                     }
-                    if let Some(task) = client.transfer_task.take() {
-                        // Give the task time to complete
-                        match tokio::time::timeout(std::time::Duration::from_secs(10), task).await {
-                            Ok(Ok(_)) => println!("Transfer task completed successfully"),
-                            Ok(Err(e)) => eprintln!("Transfer task failed: {:?}", e),
-                            Err(_) => eprintln!("Transfer task timed out"),
-                        }
+                    debug!(video = video_num, "processing last frame");
+                    if let Some(recording) = recording.take() {
+                        uploads.retain(|upload| !upload.task.is_finished());
+                        uploads.push(finish_recording(recording, status.clone()));
                     }
 
-                    println!("Cleanup completed");
+                    debug!("cleanup completed");
                 }
             }
         }
     }
+
+    // capture didnt get to end these, so they stop wherever they got to
+    if let Some(local) = local_recording.take() {
+        finish_local_recording(local, &status).await;
+    }
+    if let Some(recording) = recording.take() {
+        uploads.push(finish_recording(recording, status.clone()));
+    }
+    let Some(deadline) = deadline else {
+        error!("capture stopped");
+        return Outcome::CaptureStopped.into();
+    };
+    uploads.retain(|upload| !upload.task.is_finished());
+    if !forced {
+        if !uploads.is_empty() {
+            info!(uploads = uploads.len(), "waiting for uploads to finish");
+        }
+        let waiting = timeout_at(deadline, async {
+            for upload in uploads.iter_mut() {
+                let _ = (&mut upload.task).await;
+            }
+        });
+        tokio::select! {
+            _ = waiting => {}
+            Ok(signal) = &mut stop, if listening => {
+                warn!(signal, "stopping without waiting for uploads");
+                forced = true;
+            }
+        }
+    }
+    let unfinished = keep_unfinished(&uploads);
+    if unfinished > 0 {
+        error!(
+            unfinished,
+            "stopped with recordings still uploading, the frames they hadnt sent are in video_frames"
+        );
+    }
+    if !forced {
+        if let Err(e) = client.logout().await {
+            warn!("logging out: {e}");
+        }
+    }
+    info!("stopped");
+    if unfinished > 0 {
+        Outcome::UploadsUnfinished.into()
+    } else {
+        Outcome::Stopped.into()
+    }
+}
+
+/// a finished recording that is still uploading
+struct Upload {
+    video_num: usize,
+    task: JoinHandle<()>,
+    spool: QueueSpool,
+}
+
+/// leaves the frames that uploads still going hadnt sent in video_frames, returning how many there were
+fn keep_unfinished(uploads: &[Upload]) -> usize {
+    let mut unfinished = 0;
+    for upload in uploads.iter().filter(|upload| !upload.task.is_finished()) {
+        unfinished += 1;
+        match upload.spool.spool() {
+            Ok(frames) => warn!(video = upload.video_num, frames, "kept unsent frames"),
+            Err(e) => error!(video = upload.video_num, "keeping unsent frames: {e}"),
+        }
+    }
+    unfinished
+}
+
+/// lets the upload drain in the background so the next recording can start straight away
+fn finish_recording(recording: Recording, status: Arc<CameraStatus>) -> Upload {
+    let video_num = recording.video_num();
+    let spool = recording.spool();
+    let completion = recording.finish();
+    let task = actix_web::rt::spawn(
+        async move {
+            let result = completion.await;
+            status.uploads.fetch_sub(1, Ordering::Relaxed);
+            match result {
+                Ok(response) => info!("transfer completed successfully: {response}"),
+                Err(e) => {
+                    error!("transfer failed: {}", e);
+                    status.record_error(format!("uploading video {video_num}: {e}"));
+                }
+            }
+        }
+        .instrument(info_span!("recording", video = video_num)),
+    );
+    Upload {
+        video_num,
+        task,
+        spool,
+    }
+}
+
+async fn finish_local_recording(local: LocalRecording, status: &CameraStatus) {
+    let video_num = local.video_num();
+    match local.finish().await {
+        Ok(path) => info!(
+            video = video_num,
+            "recording kept locally at {}",
+            path.display()
+        ),
+        Err(e) => {
+            error!(video = video_num, "finishing local recording: {e}");
+            status.record_error(format!("finishing local recording: {e}"));
+        }
+    }
 }
 
 fn set_up_dirs() -> Result<(), std::io::Error> {
-    if Path::new("video_frames").exists() {
-        return Ok(());
-    } else {
-        create_dir("video_frames")?;
+    for dir in ["video_frames", "recordings"] {
+        if !Path::new(dir).exists() {
+            create_dir(dir)?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,167 @@
+use serde::Serialize;
+use std::error::Error;
+use std::sync::atomic::Ordering;
+
+use crate::adaptive::QualityChange;
+use crate::framing::encrypt_single;
+use crate::keys::KeyPair;
+use crate::motiondetection::{BoundingBox, Frame};
+use crate::upload_queue::UploadStats;
+
+/// what the server is told about a recording so events can be searched and filtered.
+/// sent as encrypted json once the recording has been uploaded
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RecordingMetadata {
+    pub camera_id: String,
+    pub video_num: usize,
+    pub fps: usize,
+
+    /// capture time of the first and last frames, in ms since the unix epoch
+    pub start_ms: u64,
+    pub end_ms: u64,
+
+    /// frames in the uploaded video, not counting ones dropped by the backpressure policy
+    pub frame_count: usize,
+    pub dropped_frames: usize,
+
+    pub peak_motion_score: u32,
+
+    /// every zone movement was seen in during the recording
+    pub zones: Vec<String>,
+
+    /// where the movement was in the frame with the peak motion score
+    pub bounding_boxes: Vec<BoundingBox>,
+
+    /// changes made by adaptive quality, see adaptive
+    pub quality_changes: Vec<QualityChange>,
+
+    pub client_version: String,
+
+    /// DetectionConfig::hash of the settings the recording was detected with
+    pub config_hash: String,
+}
+
+impl RecordingMetadata {
+    /// metadata for a recording starting with first
+    pub fn new(camera_id: &str, config_hash: &str, first: &Frame) -> Self {
+        let mut metadata = RecordingMetadata {
+            camera_id: camera_id.to_string(),
+            video_num: first.video_num,
+            fps: first.fps,
+            start_ms: first.timestamp_ms,
+            client_version: env!("CARGO_PKG_VERSION").to_string(),
+            config_hash: config_hash.to_string(),
+            ..Default::default()
+        };
+        metadata.observe(first);
+        metadata
+    }
+
+    /// takes the next frame of the recording into account
+    pub fn observe(&mut self, frame: &Frame) {
+        self.end_ms = frame.timestamp_ms;
+        if frame.motion_score >= self.peak_motion_score {
+            self.peak_motion_score = frame.motion_score;
+            if !frame.bounding_boxes.is_empty() {
+                self.bounding_boxes = frame.bounding_boxes.clone();
+            }
+        }
+        for zone in &frame.zones {
+            if !self.zones.contains(zone) {
+                self.zones.push(zone.clone());
+            }
+        }
+    }
+
+    /// fills in what is only known once the upload is done
+    pub fn finish(&mut self, stats: &UploadStats) {
+        self.dropped_frames = stats.dropped.load(Ordering::Relaxed);
+        self.frame_count = stats.frames.load(Ordering::Relaxed) - self.dropped_frames;
+        self.quality_changes = stats.quality_changes.lock().unwrap().clone();
+    }
+
+    /// the metadata as json, encrypted as a framed upload (see framing) with the json as its only frame
+    pub fn encrypt(&self, key_pair: KeyPair) -> Result<Vec<u8>, Box<dyn Error>> {
+        let json = serde_json::to_vec(self)?;
+        Ok(encrypt_single(key_pair, self.end_ms, &json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::decrypt_frames;
+    use crate::motiondetection::DetectionConfig;
+    use security_cam_common::encryption::generate_key;
+
+    fn frame(timestamp_ms: u64, motion_score: u32, bounding_boxes: Vec<BoundingBox>) -> Frame {
+        let config = DetectionConfig {
+            zones: vec![
+                "door:0,0,100,100".parse().unwrap(),
+                "yard:100,0,100,100".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+        Frame {
+            video_num: 7,
+            frame_bytes: Vec::new(),
+            fps: 25,
+            end: false,
+            timestamp_ms,
+            motion_score,
+            zones: config.zones_hit(&bounding_boxes),
+            bounding_boxes,
+        }
+    }
+
+    fn area(x: u32, y: u32) -> BoundingBox {
+        BoundingBox {
+            x,
+            y,
+            width: 10,
+            height: 10,
+        }
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let mut metadata =
+            RecordingMetadata::new("garage", "abc", &frame(1000, 20, vec![area(5, 5)]));
+        metadata.observe(&frame(1040, 90, vec![area(150, 5), area(5, 50)]));
+        metadata.observe(&frame(1080, 10, Vec::new()));
+        let stats = UploadStats::default();
+        stats.frames.store(3, Ordering::Relaxed);
+        stats.dropped.store(1, Ordering::Relaxed);
+        metadata.finish(&stats);
+
+        let key_pair = generate_key("pass").unwrap();
+        let key = key_pair.0;
+        let encrypted = metadata.encrypt(key_pair).unwrap();
+        let decrypted = decrypt_frames(&encrypted, |_| Ok(key)).unwrap();
+        assert_eq!(decrypted.len(), 1);
+        let json: serde_json::Value = serde_json::from_slice(&decrypted[0].frame).unwrap();
+
+        assert_eq!(json["camera_id"], "garage");
+        assert_eq!(json["video_num"], 7);
+        assert_eq!(json["start_ms"], 1000);
+        assert_eq!(json["end_ms"], 1080);
+        assert_eq!(json["frame_count"], 2);
+        assert_eq!(json["dropped_frames"], 1);
+        assert_eq!(json["peak_motion_score"], 90);
+        assert_eq!(json["zones"], serde_json::json!(["door", "yard"]));
+        assert_eq!(json["bounding_boxes"].as_array().unwrap().len(), 2);
+        assert_eq!(json["bounding_boxes"][0]["x"], 150);
+        assert_eq!(json["config_hash"], "abc");
+    }
+
+    #[test]
+    fn test_config_hash_changes_with_config() {
+        let config = DetectionConfig::default();
+        let more_sensitive = DetectionConfig {
+            min_score: 2,
+            ..Default::default()
+        };
+        assert_eq!(config.hash(), DetectionConfig::default().hash());
+        assert_ne!(config.hash(), more_sensitive.hash());
+    }
+}
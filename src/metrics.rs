@@ -0,0 +1,262 @@
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::health::{dir_size, CameraStatus};
+
+/// counters for the whole client, served in the prometheus text format by the local api's `/metrics`
+pub static METRICS: Metrics = Metrics::new();
+
+/// analysis time buckets, in microseconds
+const ANALYSIS_BUCKETS: &[u64] = &[
+    1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000,
+];
+
+/// motion score buckets, in changed pixels
+const SCORE_BUCKETS: &[u64] = &[0, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+/// the most buckets a histogram can have, not counting +Inf
+const MAX_BUCKETS: usize = 16;
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// a histogram of whole number observations, shown divided by scale, eg. microseconds shown as seconds
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [u64],
+    scale: f64,
+
+    /// observations at or below each bound, the rest only go in count
+    buckets: [AtomicU64; MAX_BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new(bounds: &'static [u64], scale: f64) -> Self {
+        assert!(bounds.len() <= MAX_BUCKETS);
+        Histogram {
+            bounds,
+            scale,
+            buckets: [const { AtomicU64::new(0) }; MAX_BUCKETS],
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// writes the cumulative buckets, sum and count
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = *bound as f64 / self.scale;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / self.scale;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub frames_captured: Counter,
+    pub decode_errors: Counter,
+
+    /// time from decoding a frame to having its motion score, in microseconds
+    pub analysis_time: Histogram,
+    pub motion_score: Histogram,
+
+    /// times movement started after there had been none
+    pub motion_events: Counter,
+
+    /// frames thrown away or written to disk by the upload queue's backpressure policy
+    pub frames_dropped: Counter,
+    pub frames_spilled: Counter,
+
+    /// recording bytes sent to the server, after encryption
+    pub upload_bytes: Counter,
+    pub upload_failures: Counter,
+    pub upload_retries: Counter,
+    pub login_failures: Counter,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            frames_captured: Counter::new(),
+            decode_errors: Counter::new(),
+            analysis_time: Histogram::new(ANALYSIS_BUCKETS, 1_000_000.0),
+            motion_score: Histogram::new(SCORE_BUCKETS, 1.0),
+            motion_events: Counter::new(),
+            frames_dropped: Counter::new(),
+            frames_spilled: Counter::new(),
+            upload_bytes: Counter::new(),
+            upload_failures: Counter::new(),
+            upload_retries: Counter::new(),
+            login_failures: Counter::new(),
+        }
+    }
+
+    /// the metrics along with gauges read from status and the spool in spool_dir, in the prometheus text format
+    pub fn render(&self, status: &CameraStatus, spool_dir: &Path) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "security_cam_frames_captured_total",
+                "Frames read from the camera.",
+                &self.frames_captured,
+            ),
+            (
+                "security_cam_frame_decode_errors_total",
+                "Frames from the camera that couldnt be decoded.",
+                &self.decode_errors,
+            ),
+            (
+                "security_cam_motion_events_total",
+                "Times movement was detected after there had been none.",
+                &self.motion_events,
+            ),
+            (
+                "security_cam_frames_dropped_total",
+                "Frames thrown away because uploads couldnt keep up.",
+                &self.frames_dropped,
+            ),
+            (
+                "security_cam_frames_spilled_total",
+                "Frames written to disk because uploads couldnt keep up.",
+                &self.frames_spilled,
+            ),
+            (
+                "security_cam_upload_bytes_total",
+                "Encrypted recording bytes sent to the server.",
+                &self.upload_bytes,
+            ),
+            (
+                "security_cam_upload_failures_total",
+                "Recordings that failed to upload.",
+                &self.upload_failures,
+            ),
+            (
+                "security_cam_upload_retries_total",
+                "Upload requests that were tried again after failing.",
+                &self.upload_retries,
+            ),
+            (
+                "security_cam_login_failures_total",
+                "Failed logins to the server.",
+                &self.login_failures,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            header(&mut out, name, help, "counter");
+            let _ = writeln!(out, "{name} {}", counter.get());
+        }
+        self.analysis_time.render(
+            &mut out,
+            "security_cam_frame_analysis_seconds",
+            "Time taken to work out a frame's motion score.",
+        );
+        self.motion_score.render(
+            &mut out,
+            "security_cam_motion_score",
+            "Changed pixels between consecutive frames.",
+        );
+
+        let gauges = [
+            (
+                "security_cam_capture_fps",
+                "Frames captured in the last second.",
+                status.capture_fps.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "security_cam_uploads_in_flight",
+                "Recordings started that havent finished uploading.",
+                status.uploads.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "security_cam_spool_bytes",
+                "Bytes of frames waiting on disk.",
+                dir_size(spool_dir),
+            ),
+            (
+                "security_cam_uptime_seconds",
+                "Seconds since the client started.",
+                status.uptime().as_secs(),
+            ),
+        ];
+        for (name, help, value) in gauges {
+            header(&mut out, name, help, "gauge");
+            let _ = writeln!(out, "{name} {value}");
+        }
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.frames_captured.add(3);
+        metrics.analysis_time.observe(2_000);
+        metrics.analysis_time.observe(30_000);
+        metrics.analysis_time.observe(5_000_000);
+
+        let text = metrics.render(&CameraStatus::default(), &std::env::temp_dir());
+        assert!(text.contains(
+            "# TYPE security_cam_frames_captured_total counter\nsecurity_cam_frames_captured_total 3\n"
+        ));
+        assert!(text.contains("security_cam_frame_analysis_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(text.contains("security_cam_frame_analysis_seconds_bucket{le=\"0.0025\"} 1\n"));
+        assert!(text.contains("security_cam_frame_analysis_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(text.contains("security_cam_frame_analysis_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(text.contains("security_cam_frame_analysis_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("security_cam_frame_analysis_seconds_sum 5.032\n"));
+        assert!(text.contains("security_cam_frame_analysis_seconds_count 3\n"));
+        assert!(text.contains("security_cam_uploads_in_flight 0\n"));
+    }
+}
@@ -0,0 +1,158 @@
+use security_cam_common::shuttle_runtime::tokio;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// minimal stand in for the camera server, for tests. implements the chunked upload endpoints
+/// (see chunked::ChunkedUpload) and can drop connections to test that uploads resume
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    /// segments of each chunked upload, keyed by `<video>/<fps>`
+    segments: HashMap<String, Vec<Vec<u8>>>,
+
+    /// finished chunked uploads with their segments put back together
+    finished: HashMap<String, Vec<u8>>,
+
+    /// how many of the next segments to drop the connection on before storing them
+    drop_before_store: usize,
+
+    /// how many of the next segments to store but drop the connection on before acknowledging them
+    drop_before_ack: usize,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+impl MockServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let server_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let Some(request) = read_request(&mut stream).await else {
+                    continue;
+                };
+                if let Some(response) = handle(&server_state, request) {
+                    let _ = stream.write_all(response.as_bytes()).await;
+                }
+                let _ = stream.shutdown().await;
+            }
+        });
+        MockServer { addr, state }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    pub fn drop_before_store(&self, segments: usize) {
+        self.state.lock().unwrap().drop_before_store = segments;
+    }
+
+    pub fn drop_before_ack(&self, segments: usize) {
+        self.state.lock().unwrap().drop_before_ack = segments;
+    }
+
+    /// the finished chunked upload for `<video>/<fps>`
+    pub fn finished_upload(&self, upload: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().finished.get(upload).cloned()
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    let head_len = loop {
+        if let Some(i) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break i + 4;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    };
+    let head = String::from_utf8_lossy(&request[..head_len]).to_string();
+    let content_length = head
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse::<usize>().ok())?
+        })
+        .unwrap_or(0);
+    while request.len() < head_len + content_length {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let mut request_line = head.split_whitespace();
+    Some(Request {
+        method: request_line.next()?.to_string(),
+        path: request_line.next()?.to_string(),
+        body: request[head_len..].to_vec(),
+    })
+}
+
+fn response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// returns the response to send, or None to drop the connection
+fn handle(state: &Mutex<MockState>, request: Request) -> Option<String> {
+    let mut state = state.lock().unwrap();
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let ["upload_chunked", video, fps, action] = segments.as_slice() else {
+        return Some(response("404 Not Found", ""));
+    };
+    let upload = format!("{video}/{fps}");
+    match (request.method.as_str(), *action) {
+        ("GET", "status") => {
+            let stored = state.segments.get(&upload).map_or(0, Vec::len);
+            Some(response("200 OK", &stored.to_string()))
+        }
+        ("POST", "finish") => {
+            let segments = state.segments.remove(&upload).unwrap_or_default();
+            let count = segments.len();
+            state.finished.insert(upload, segments.concat());
+            Some(response("200 OK", &format!("{count} segments")))
+        }
+        ("PUT", seq) => {
+            let Ok(seq) = seq.parse::<usize>() else {
+                return Some(response("400 Bad Request", ""));
+            };
+            if state.drop_before_store > 0 {
+                state.drop_before_store -= 1;
+                return None;
+            }
+            let stored = state.segments.entry(upload).or_default();
+            if seq > stored.len() {
+                return Some(response("409 Conflict", &stored.len().to_string()));
+            }
+            if seq == stored.len() {
+                stored.push(request.body);
+            }
+            if state.drop_before_ack > 0 {
+                state.drop_before_ack -= 1;
+                return None;
+            }
+            Some(response("200 OK", &seq.to_string()))
+        }
+        _ => Some(response("404 Not Found", "")),
+    }
+}
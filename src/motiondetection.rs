@@ -1,15 +1,24 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::{Cursor, Seek, Write};
+use std::str::FromStr;
 
 use image::codecs::jpeg::JpegDecoder;
 use image::{DynamicImage, GenericImage, GenericImageView, GrayImage, ImageBuffer, Luma, Pixel};
 use imageproc::contrast::threshold;
+use imageproc::region_labelling::{connected_components, Connectivity};
 use imageproc::utils::Diff;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use security_cam_common::shuttle_runtime::tokio::sync::mpsc::{channel, Receiver, Sender};
+use security_cam_common::shuttle_runtime::tokio::sync::watch;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, trace};
 use v4l::buffer::Type;
 use v4l::io::traits::CaptureStream;
 use v4l::io::userptr::Stream;
@@ -18,14 +27,234 @@ use v4l::video::Capture;
 use v4l::Device;
 use v4l::FourCC;
 
+use crate::health::CameraStatus;
+use crate::logging::RateLimit;
+use crate::metrics::METRICS;
+
 const THRESHOLD_VALUE: i32 = 60;
 
+/// how many frames can wait for the main thread before the capture thread blocks
+const CAPTURE_QUEUE_FRAMES: usize = 64;
+
+/// changed areas smaller than this many pixels dont get a bounding box
+const MIN_BLOB_PIXELS: u32 = 4;
+
+/// at most this many bounding boxes are kept for each frame, biggest first
+const MAX_BOXES: usize = 16;
+
+/// movement going on is logged at most this often, its start always is
+const MOVEMENT_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// environment variable holding how different a pixel has to be between frames to count as changed, 0-255
+pub const THRESHOLD_VAR: &str = "SECURITY_CAM_MOTION_THRESHOLD";
+
+/// environment variable holding how many changed pixels it takes to count as movement
+pub const MIN_SCORE_VAR: &str = "SECURITY_CAM_MOTION_MIN_SCORE";
+
+/// environment variable holding `;` separated `<name>:<x>,<y>,<width>,<height>` zones in pixels,
+/// eg. `door:0,0,320,240; driveway:320,0,320,480`
+pub const ZONES_VAR: &str = "SECURITY_CAM_ZONES";
+
+/// environment variable holding `;` separated `<x>,<y>,<width>,<height>` areas in pixels where movement is ignored,
+/// eg. a tree or a road
+pub const MASKS_VAR: &str = "SECURITY_CAM_MASKS";
+
+/// parameters for telling movement apart from noise
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DetectionConfig {
+    pub threshold: u8,
+    pub min_score: u32,
+
+    /// named parts of the frame, recordings list the ones movement was seen in
+    pub zones: Vec<Zone>,
+
+    /// parts of the frame where changes dont count as movement
+    pub masks: Vec<BoundingBox>,
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        DetectionConfig {
+            threshold: THRESHOLD_VALUE as u8,
+            min_score: 5,
+            zones: Vec::new(),
+            masks: Vec::new(),
+        }
+    }
+}
+
+impl DetectionConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let mut config = DetectionConfig::default();
+        if let Ok(threshold) = std::env::var(THRESHOLD_VAR) {
+            config.threshold = threshold
+                .trim()
+                .parse()
+                .map_err(|_| format!("{THRESHOLD_VAR} must be between 0 and 255"))?;
+        }
+        if let Ok(min_score) = std::env::var(MIN_SCORE_VAR) {
+            config.min_score = min_score
+                .trim()
+                .parse()
+                .map_err(|_| format!("{MIN_SCORE_VAR} must be a positive integer"))?;
+        }
+        if let Ok(zones) = std::env::var(ZONES_VAR) {
+            config.zones = zones
+                .split(';')
+                .filter(|zone| !zone.trim().is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("{ZONES_VAR}: {e}"))?;
+        }
+        if let Ok(masks) = std::env::var(MASKS_VAR) {
+            config.masks = masks
+                .split(';')
+                .filter(|mask| !mask.trim().is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("{MASKS_VAR}: {e}"))?;
+        }
+        Ok(config)
+    }
+
+    /// sha256 of the config, so recordings can be matched up with the settings they were detected with
+    pub fn hash(&self) -> String {
+        let json = serde_json::to_vec(self).expect("detection config always serializes");
+        Sha256::digest(json)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// clears the masked parts of a thresholded difference image so they dont add to the score
+    pub fn apply_masks(&self, image: &mut GrayImage) {
+        for mask in &self.masks {
+            // masks can come from the server, so they can reach past the end of a u32
+            let right = mask.x.saturating_add(mask.width).min(image.width());
+            let bottom = mask.y.saturating_add(mask.height).min(image.height());
+            for y in mask.y.min(bottom)..bottom {
+                for x in mask.x.min(right)..right {
+                    image.put_pixel(x, y, Luma([0]));
+                }
+            }
+        }
+    }
+
+    /// names of the zones any of boxes overlap
+    pub fn zones_hit(&self, boxes: &[BoundingBox]) -> Vec<String> {
+        self.zones
+            .iter()
+            .filter(|zone| boxes.iter().any(|b| b.intersects(&zone.area)))
+            .map(|zone| zone.name.clone())
+            .collect()
+    }
+}
+
+/// a rectangle of the frame, in pixels, written like `0,0,320,240`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl BoundingBox {
+    pub fn intersects(&self, other: &BoundingBox) -> bool {
+        self.x < other.x.saturating_add(other.width)
+            && other.x < self.x.saturating_add(self.width)
+            && self.y < other.y.saturating_add(other.height)
+            && other.y < self.y.saturating_add(self.height)
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let numbers = s
+            .split(',')
+            .map(|n| n.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("expected `<x>,<y>,<width>,<height>`, got {s}"))?;
+        let [x, y, width, height] = numbers[..] else {
+            return Err(format!("expected `<x>,<y>,<width>,<height>`, got {s}").into());
+        };
+        Ok(BoundingBox {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+/// a named part of the frame, written like `door:0,0,320,240`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Zone {
+    pub name: String,
+    pub area: BoundingBox,
+}
+
+impl FromStr for Zone {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, area) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("expected `<name>:<x>,<y>,<width>,<height>`, got {s}"))?;
+        let area = area
+            .parse()
+            .map_err(|_| format!("zone {name} must be 4 numbers"))?;
+        Ok(Zone {
+            name: name.trim().to_string(),
+            area,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub video_num: usize,
     pub frame_bytes: Vec<u8>,
     pub fps: usize,
     pub end: bool,
+
+    /// when the frame was captured, in ms since the unix epoch
+    pub timestamp_ms: u64,
+
+    /// changed pixels between this frame and the ones before it
+    pub motion_score: u32,
+
+    /// where in the frame the changes were
+    pub bounding_boxes: Vec<BoundingBox>,
+
+    /// names of the zones the changes were in
+    pub zones: Vec<String>,
+}
+
+/// the latest frame off the camera, whether or not there was movement in it
+#[derive(Debug, Clone)]
+pub struct LiveFrame {
+    /// when the frame was captured, in ms since the unix epoch
+    pub timestamp_ms: u64,
+    pub jpeg: Vec<u8>,
+    pub motion_score: u32,
+}
+
+/// a frame with what motion detection made of it, for previewing detection settings
+#[derive(Debug, Clone)]
+pub struct PreviewFrame {
+    /// when the frame was captured, in ms since the unix epoch
+    pub timestamp_ms: u64,
+    pub jpeg: Vec<u8>,
+    pub motion_score: u32,
+
+    /// white where pixels counted towards motion_score
+    pub motion_mask: GrayImage,
+    pub bounding_boxes: Vec<BoundingBox>,
 }
 
 /// Error contains any error message thrown during the frame reading loop
@@ -46,8 +275,8 @@ pub enum FrameCommand {
 
 /// used for connecting to /dev/video0 and reading in frames to detect any motion
 pub struct MotionDetector {
-    /// filenames are sent through this channel
-    tx: Sender<FrameCommand>,
+    /// filenames are sent through this channel, None once stopped so the channel closes when the capture thread exits
+    tx: Option<Sender<FrameCommand>>,
 
     /// filenames received through this channel
     rx: Receiver<FrameCommand>,
@@ -59,39 +288,104 @@ pub struct MotionDetector {
 
     ///when to stop recording after processing frames
     buffer_delay: Duration,
+
+    /// changes are picked up by the capture thread from the next frame on, see config_handle
+    config: watch::Sender<DetectionConfig>,
+
+    /// every captured frame is put here while anything is subscribed to it, see subscribe_live
+    live_tx: watch::Sender<Option<LiveFrame>>,
+
+    /// like live_tx with what detection saw in the frame, see preview_handle
+    preview_tx: watch::Sender<Option<PreviewFrame>>,
+
+    /// kept up to date by the capture thread
+    status: Arc<CameraStatus>,
+
+    /// set by stop, the capture thread exits once any recording in progress has been ended
+    stopping: Arc<AtomicBool>,
 }
 
 impl MotionDetector {
     pub fn new(video_device: u32) -> Self {
-        let (tx, rx) = channel();
+        Self::with_config(video_device, DetectionConfig::default())
+    }
+
+    pub fn with_config(video_device: u32, config: DetectionConfig) -> Self {
+        let (tx, rx) = channel(CAPTURE_QUEUE_FRAMES);
         Self {
-            tx,
+            tx: Some(tx),
             rx,
             video_device,
             motion_detection_thread: None,
             buffer_delay: Duration::from_secs(5),
+            config: watch::Sender::new(config),
+            live_tx: watch::Sender::new(None),
+            preview_tx: watch::Sender::new(None),
+            status: Arc::new(CameraStatus::default()),
+            stopping: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// changes the detection settings of a running detector without restarting capture
+    pub fn config_handle(&self) -> watch::Sender<DetectionConfig> {
+        self.config.clone()
+    }
+
+    /// capture fps, last frame time and whether there is movement, for reporting on the camera
+    pub fn status(&self) -> Arc<CameraStatus> {
+        self.status.clone()
+    }
+
+    /// watches the frames coming off the camera, motion or not. only the latest frame is kept,
+    /// so a slow subscriber skips frames instead of holding up capture
+    pub fn subscribe_live(&self) -> watch::Receiver<Option<LiveFrame>> {
+        self.live_tx.subscribe()
+    }
+
+    /// frames with their motion mask and bounding boxes, only worked out while something is subscribed, so
+    /// subscribe for as long as they are needed and no longer
+    pub fn preview_handle(&self) -> watch::Sender<Option<PreviewFrame>> {
+        self.preview_tx.clone()
+    }
+
     /// if there is a new motion capture saved, this function will return its file path, if not, it will return None
+    /// blocks the thread, so it must not be called from async code, use next_command there
     pub fn ask_for_filename(&mut self) -> Option<FrameCommand> {
-        self.rx.recv().ok()
+        self.rx.blocking_recv()
+    }
+
+    /// waits for the next motion capture or error without holding up the rest of the runtime
+    pub async fn next_command(&mut self) -> Option<FrameCommand> {
+        self.rx.recv().await
+    }
+
+    /// stops capture after the next frame. a recording in progress is ended with that frame, marked as the end,
+    /// and next_command returns None once the capture thread has exited
+    pub fn stop(&mut self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        self.tx = None;
     }
 
     pub fn start_detection(&mut self) -> Result<(), Box<dyn Error>> {
         if self.motion_detection_thread.is_some() {
             return Err("already started".into());
         }
+        let tx = self.tx.clone().ok_or("already stopped")?;
         let mut device = Device::new(self.video_device as usize)?;
         let mut format = device.format()?;
         format.fourcc = FourCC::new(b"MJPG");
         format = device.set_format(&format)?;
-        println!("{:?}", format);
+        info!(?format, "capture format");
         let params = device.params()?;
         let mut stream = UserptrStream::new(&device, Type::VideoCapture)?;
 
         // send FileCommands through tx to interact with the main thread
-        let tx = self.tx.clone();
+        let stopping = self.stopping.clone();
+        let live_tx = self.live_tx.clone();
+        let preview_tx = self.preview_tx.clone();
+        let status = self.status.clone();
+        let mut config_rx = self.config.subscribe();
+        let mut config = config_rx.borrow_and_update().clone();
 
         // the diffs of f1 and f2, and f2 and f3 are used to see if motion is detece
         let mut frame1: Option<ImageBuffer<Luma<u8>, Vec<u8>>> = None;
@@ -106,19 +400,37 @@ impl MotionDetector {
         let mut framerate_time = Instant::now();
         let mut framerate_counter = 0;
         let mut fps = 25;
+        let movement_log = RateLimit::new(MOVEMENT_LOG_INTERVAL);
+        // the capture thread logs under the span start_detection was called in, eg. the camera's
+        let span = tracing::Span::current();
         self.motion_detection_thread = Some(thread::spawn(move || {
-            println!("started thread");
+            let _span = span.entered();
+            debug!("started capture thread");
             // ----------------------------------------------------------------
             // -------------------FRAME PROCESSING LOOP -----------------------
             // ----------------------------------------------------------------
             loop {
+                // once stopping, capture carries on only to end the recording in progress
+                let stopping = stopping.load(Ordering::Relaxed);
+                if stopping && last_movement.is_none() {
+                    debug!("stopped capture thread");
+                    break;
+                }
                 let buf = if let Ok((buf, _)) = stream.next() {
                     buf
                 } else {
-                    tx.send(FrameCommand::Error("failed to capture frame".to_string()))
+                    tx.blocking_send(FrameCommand::Error("failed to capture frame".to_string()))
                         .expect("failed to send error");
                     continue;
                 };
+                let timestamp_ms = unix_millis();
+                METRICS.frames_captured.inc();
+                status.last_frame_ms.store(timestamp_ms, Ordering::Relaxed);
+                if config_rx.has_changed().unwrap_or(false) {
+                    config = config_rx.borrow_and_update().clone();
+                    info!(?config, "detection settings changed");
+                }
+                let analysis_started = Instant::now();
                 match decode(buf) {
                     Ok(frame_dynamic) => {
                         let frame = frame_dynamic.to_luma8();
@@ -126,6 +438,7 @@ impl MotionDetector {
                         frame1 = frame2;
                         frame2 = frame3;
                         frame3 = Some(frame);
+                        let mut live_score = 0;
                         if let (Some(f1), Some(f2), Some(f3)) = (&frame1, &frame2, &frame3) {
                             // Calculate the difference between f2 and f1, and between f3 and f2
                             let diff1 = pixel_diffs(f2, f1, |(x1, y1, p1), (x2, y2, p2)| {
@@ -139,30 +452,61 @@ impl MotionDetector {
                             let diff1 = diffs_to_gray_image(diff1, f3.width(), f3.height());
                             let diff2 = diffs_to_gray_image(diff2, f3.width(), f3.height());
                             // Threshold the differences
-                            let thresholded_diff1 = threshold(
+                            let mut thresholded_diff1 = threshold(
                                 &diff1,
-                                THRESHOLD_VALUE as u8,
+                                config.threshold,
                                 imageproc::contrast::ThresholdType::Binary,
                             );
-                            let thresholded_diff2 = threshold(
+                            let mut thresholded_diff2 = threshold(
                                 &diff2,
-                                THRESHOLD_VALUE as u8,
+                                config.threshold,
                                 imageproc::contrast::ThresholdType::Binary,
                             );
 
+                            config.apply_masks(&mut thresholded_diff1);
+                            config.apply_masks(&mut thresholded_diff2);
+
                             // Combine the differences with a logical AND
                             let score = movement_score(&thresholded_diff1, &thresholded_diff2);
+                            live_score = score;
+                            status.motion_score.store(score, Ordering::Relaxed);
+                            METRICS
+                                .analysis_time
+                                .observe(analysis_started.elapsed().as_micros() as u64);
+                            METRICS.motion_score.observe(score as u64);
+                            if preview_tx.receiver_count() > 0 {
+                                let motion_mask =
+                                    motion_mask(&thresholded_diff1, &thresholded_diff2);
+                                preview_tx.send_replace(Some(PreviewFrame {
+                                    timestamp_ms,
+                                    jpeg: buf.to_vec(),
+                                    motion_score: score,
+                                    bounding_boxes: mask_boxes(&motion_mask),
+                                    motion_mask,
+                                }));
+                            }
+                            let (bounding_boxes, zones) = if last_movement.is_some() {
+                                let boxes = motion_boxes(&thresholded_diff1, &thresholded_diff2);
+                                let zones = config.zones_hit(&boxes);
+                                (boxes, zones)
+                            } else {
+                                Default::default()
+                            };
                             if let Some(time) = last_movement {
                                 let time = time.elapsed().as_secs();
-                                if time < 3 {
+                                if time < 3 && !stopping {
                                     // if movement is still going on
                                     let filename =
                                         gen_filename(&mut framecounter, &mut videocounter);
-                                    tx.send(FrameCommand::SingleFrame(Frame {
+                                    tx.blocking_send(FrameCommand::SingleFrame(Frame {
                                         video_num: videocounter,
                                         frame_bytes: buf.to_vec(),
                                         fps,
                                         end: false,
+                                        timestamp_ms,
+                                        motion_score: score,
+                                        bounding_boxes,
+                                        zones,
                                     }))
                                     .expect("failed to send frame");
                                     framecounter += 1;
@@ -173,11 +517,15 @@ impl MotionDetector {
                                     //     framecounter,
                                     //     fps,
                                     // ));
-                                    tx.send(FrameCommand::SingleFrame(Frame {
+                                    tx.blocking_send(FrameCommand::SingleFrame(Frame {
                                         video_num: videocounter,
                                         frame_bytes: buf.to_vec(),
                                         fps: fps,
                                         end: true,
+                                        timestamp_ms,
+                                        motion_score: score,
+                                        bounding_boxes,
+                                        zones,
                                     }))
                                     .expect("failed to send frame");
                                     last_movement = None;
@@ -186,14 +534,30 @@ impl MotionDetector {
                                 }
                             }
 
-                            if score > 5 {
-                                println!("movement detected!");
+                            if score > config.min_score && !stopping {
+                                if last_movement.is_none() {
+                                    info!(score, video = videocounter, "movement detected");
+                                    METRICS.motion_events.inc();
+                                } else if let Some(suppressed) = movement_log.check() {
+                                    debug!(score, suppressed, "movement continuing");
+                                }
                                 last_movement = Some(Instant::now());
                             }
+                            status
+                                .motion
+                                .store(last_movement.is_some(), Ordering::Relaxed);
+                        }
+                        if live_tx.receiver_count() > 0 {
+                            live_tx.send_replace(Some(LiveFrame {
+                                timestamp_ms,
+                                jpeg: buf.to_vec(),
+                                motion_score: live_score,
+                            }));
                         }
                     }
                     Err(e) => {
-                        tx.send(FrameCommand::Error(e.to_string()))
+                        METRICS.decode_errors.inc();
+                        tx.blocking_send(FrameCommand::Error(e.to_string()))
                             .expect("failed to send frame error");
                     }
                 }
@@ -202,7 +566,8 @@ impl MotionDetector {
                 // Calculate frame rate every second
                 if framerate_time.elapsed().as_secs() >= 1 {
                     fps = framerate_counter;
-                    println!("FPS: {}", fps);
+                    status.capture_fps.store(fps, Ordering::Relaxed);
+                    trace!(fps, "capture rate");
                     // Reset counter and timer
                     framerate_counter = 0;
                     framerate_time = Instant::now();
@@ -274,6 +639,64 @@ fn diffs_to_gray_image(diffs: Vec<Diff<Luma<u8>>>, width: u32, height: u32) -> G
     grey_image
 }
 
+/// bounding boxes of the areas that changed in both thresholded diffs, biggest first
+pub fn motion_boxes(diff1: &GrayImage, diff2: &GrayImage) -> Vec<BoundingBox> {
+    mask_boxes(&motion_mask(diff1, diff2))
+}
+
+/// the pixels that changed in both thresholded diffs, the ones that count towards the motion score
+pub fn motion_mask(diff1: &GrayImage, diff2: &GrayImage) -> GrayImage {
+    GrayImage::from_fn(diff1.width(), diff1.height(), |x, y| {
+        if diff1.get_pixel(x, y)[0] & diff2.get_pixel(x, y)[0] != 0 {
+            Luma([255])
+        } else {
+            Luma([0])
+        }
+    })
+}
+
+/// bounding boxes of the blobs in a motion mask, biggest first
+pub fn mask_boxes(mask: &GrayImage) -> Vec<BoundingBox> {
+    let labels = connected_components(mask, Connectivity::Eight, Luma([0u8]));
+
+    // label -> (min x, min y, max x, max y, pixels)
+    let mut blobs: HashMap<u32, (u32, u32, u32, u32, u32)> = HashMap::new();
+    for (x, y, label) in labels.enumerate_pixels() {
+        if label[0] == 0 {
+            continue;
+        }
+        let blob = blobs.entry(label[0]).or_insert((x, y, x, y, 0));
+        blob.0 = blob.0.min(x);
+        blob.1 = blob.1.min(y);
+        blob.2 = blob.2.max(x);
+        blob.3 = blob.3.max(y);
+        blob.4 += 1;
+    }
+    let mut blobs = blobs
+        .into_values()
+        .filter(|blob| blob.4 >= MIN_BLOB_PIXELS)
+        .collect::<Vec<_>>();
+    blobs.sort_by(|a, b| b.4.cmp(&a.4));
+    blobs
+        .into_iter()
+        .take(MAX_BOXES)
+        .map(|(min_x, min_y, max_x, max_y, _)| BoundingBox {
+            x: min_x,
+            y: min_y,
+            width: max_x - min_x + 1,
+            height: max_y - min_y + 1,
+        })
+        .collect()
+}
+
+/// the current time in ms since the unix epoch
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or(0)
+}
+
 /// decodes a buffer into a dynamicimage
 fn decode(jpg: &[u8]) -> Result<DynamicImage, Box<dyn Error>> {
     let decoder = JpegDecoder::new(Cursor::new(jpg))?;
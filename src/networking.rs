@@ -1,92 +1,252 @@
-use actix_web::rt::task::JoinHandle;
 use actix_web::web::Bytes;
-use awc::error::{WsClientError, WsProtocolError};
-use awc::ws::Message;
-use awc::ClientResponse;
 use reqwest::redirect::Policy;
 use reqwest::{Body, Url};
-use reqwest_websocket::{RequestBuilderExt, UpgradedRequestBuilder, WebSocket};
 use security_cam_common::encryption::FrameReader;
 use security_cam_common::encryption::*;
-use security_cam_common::futures::{Sink, SinkExt, StreamExt, TryStreamExt};
+use security_cam_common::futures::{Stream, StreamExt};
 use security_cam_common::shuttle_runtime::tokio::fs::File;
 use security_cam_common::shuttle_runtime::tokio::sync::mpsc::{channel, Receiver, Sender};
+use security_cam_common::shuttle_runtime::tokio::sync::{watch, Semaphore};
 use security_cam_common::shuttle_runtime::tokio::{self, fs};
 use security_cam_common::tokio_stream::wrappers::ReceiverStream;
 use std::collections::HashMap;
 use std::error::Error;
 use std::io::ErrorKind::NotFound;
-use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Duration;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::adaptive::{reencode, AdaptiveQuality};
+use crate::bandwidth::{limit_stream, BandwidthLimiter};
+use crate::chunked::{upload_chunked, ChunkedUpload};
+use crate::config::ClientConfig;
+use crate::control::{ControlConfig, Controller};
+use crate::framing::encrypt_frames;
+use crate::health::{CameraStatus, Heartbeat, HeartbeatConfig};
+use crate::keys::{KeyCache, KeyPair};
+use crate::liveview::{LiveView, LiveViewConfig};
+use crate::metadata::RecordingMetadata;
+use crate::metrics::METRICS;
+use crate::motiondetection::{DetectionConfig, Frame, LiveFrame};
+use crate::recording::{LocalRecording, Recording};
+use crate::remote_config::{load_version, RemoteConfig};
+use crate::thumbnail::ThumbnailUpload;
+use crate::upload_queue::{upload_queue, UploadConfig, UploadFormat, UploadReceiver};
+
+/// header carrying the identifier of the passphrase a video was encrypted with
+pub const KEY_ID_HEADER: &str = "X-Key-Id";
 
-use crate::motiondetection::Frame;
 pub struct Client<'a> {
     addr: Url,
     username: &'a str,
     password: &'a str,
+
+    /// passphrase the video is encrypted with, never sent to the server
+    encryption_password: &'a str,
+
+    /// identifies which encryption passphrase was used, sent with every upload
+    key_id: &'a str,
+
+    /// keys derived ahead of time from encryption_password
+    keys: OnceLock<Arc<KeyCache>>,
     client: reqwest::Client,
-    pub tx: Option<Sender<Result<Bytes, std::io::Error>>>,
-    pub frame_stream: Option<Pin<Box<ReceiverStream<Result<Bytes, std::io::Error>>>>>,
-    pub transfer_task: Option<JoinHandle<Result<(), Box<dyn std::error::Error>>>>,
-    // client_awc: awc::Client,
-    // websocket_connection_awc: Option<Box<dyn Sink<Message, Error = WsProtocolError> + Unpin>>,
-    // websocket_connection: Option<reqwest_websocket::WebSocket>,
+
+    /// how frames are queued for the transfer task when the upload falls behind
+    upload_config: UploadConfig,
+
+    /// which camera uploads come from, used to pick the per camera bandwidth limit
+    camera_id: String,
+    bandwidth: Arc<BandwidthLimiter>,
+
+    /// the settings recordings are detected with, their hash is sent in the metadata
+    detection: watch::Receiver<DetectionConfig>,
+
+    /// one permit per recording that is allowed to upload at the same time
+    upload_slots: Arc<Semaphore>,
+
+    /// frame rate cap and time limit for live view
+    live_config: LiveViewConfig,
+    control_config: ControlConfig,
+    heartbeat_config: HeartbeatConfig,
+
+    /// only used by the deprecated send_frame_framereader
+    recording: Option<Recording>,
 }
 
 impl<'a> Client<'a> {
-    // pub async fn close_ws(&mut self) -> Result<(), Box<dyn Error>> {
-    //     if let Some(ws) = self.websocket_connection.take() {
-    //         ws.close(reqwest_websocket::CloseCode::Normal, None).await?;
-    //     }
-    //     if let Some(mut ws) = self.websocket_connection_awc.take() {
-    //         ws.close().await?;
-    //     }
-    //     self.websocket_connection = None;
-    //     self.websocket_connection_awc = None;
-    //     Ok(())
-    // }
-
     pub async fn new(addr: &'a str, username: &'a str, password: &'a str) -> Client<'a> {
         // doesnt need to be a recoverable error because if it fails then we want our whole program to exit anyways
-        let client_with_cookies = reqwest::Client::builder()
+        Self::with_config(addr, username, password, &ClientConfig::default())
+            .await
+            .expect("couldn't build http client")
+    }
+
+    /// like new, but with the tls and proxy options in config. fails if the certificates or keys it points to
+    /// cant be loaded or the proxy url is invalid
+    pub async fn with_config(
+        addr: &'a str,
+        username: &'a str,
+        password: &'a str,
+        config: &ClientConfig,
+    ) -> Result<Client<'a>, Box<dyn Error>> {
+        let mut builder = reqwest::Client::builder()
             .redirect(Policy::limited(2))
             .cookie_store(true)
-            .tcp_keepalive(Duration::from_secs(300))
-            .build()
-            .expect("couldn't build http client");
-        Client {
-            addr: Url::parse(addr).expect("invalid url"),
+            .tcp_keepalive(Duration::from_secs(300));
+        if !config.tls.is_default() {
+            builder = builder.use_preconfigured_tls(config.tls.build()?);
+        }
+        builder = config.proxy.apply(builder)?;
+        let client_with_cookies = builder.build()?;
+        Ok(Client {
+            addr: Url::parse(addr)?,
             username,
             password,
+            encryption_password: password,
+            key_id: crate::credentials::DEFAULT_KEY_ID,
+            keys: OnceLock::new(),
             client: client_with_cookies,
-            tx: None,
-            frame_stream: None,
-            transfer_task: None,
-            // client_awc,
-            // websocket_connection_awc: None,
-            // websocket_connection: None,
+            upload_config: config.upload.clone(),
+            camera_id: config.camera_id.clone(),
+            detection: watch::channel(config.detection.clone()).1,
+            bandwidth: Arc::new(BandwidthLimiter::new(&config.bandwidth)),
+            upload_slots: Arc::new(Semaphore::new(config.upload.max_concurrent)),
+            live_config: config.live.clone(),
+            control_config: config.control.clone(),
+            heartbeat_config: config.heartbeat.clone(),
+            recording: None,
+        })
+    }
+
+    /// encrypt videos with a passphrase other than the login password, so the server never sees it.
+    /// key_id is sent along with uploads so older videos can still be decrypted after the passphrase changes
+    pub fn set_encryption_password(&mut self, encryption_password: &'a str, key_id: &'a str) {
+        self.encryption_password = encryption_password;
+        self.key_id = key_id;
+        self.keys = OnceLock::new();
+    }
+
+    /// encrypt videos with a raw 256 bit key, eg. one loaded with keys::read_key_file, instead of a passphrase
+    pub fn set_encryption_key(&mut self, key: [u8; 32], key_id: &'a str) {
+        self.key_id = key_id;
+        self.keys = OnceLock::from(Arc::new(KeyCache::from_raw_key(key)));
+    }
+
+    /// starts deriving encryption keys in the background, so the first recording doesnt wait on the key derivation
+    pub fn prepare_keys(&self) {
+        self.key_cache();
+    }
+
+    fn key_cache(&self) -> Arc<KeyCache> {
+        self.keys
+            .get_or_init(|| Arc::new(KeyCache::new(self.encryption_password)))
+            .clone()
+    }
+
+    /// keeps recording metadata in line with detection settings changed while running, eg. by
+    /// MotionDetector::config_handle
+    pub fn follow_detection_config(&mut self, detection: watch::Receiver<DetectionConfig>) {
+        self.detection = detection;
+    }
+
+    /// live view of frames, streamed when the server asks for it
+    pub fn live_view(&self, frames: watch::Receiver<Option<LiveFrame>>) -> LiveView {
+        LiveView {
+            client: self.client.clone(),
+            addr: self.addr.clone(),
+            camera_id: self.camera_id.clone(),
+            key_id: self.key_id.to_string(),
+            keys: self.key_cache(),
+            config: self.live_config.clone(),
+            frames,
+            session: None,
         }
     }
 
-    pub async fn login(&self) -> Result<(), Box<dyn Error>> {
-        let mut params = HashMap::new();
-        params.insert("username", self.username);
-        params.insert("password", self.password);
-        let resp = self
-            .client
-            .post(self.addr.join("login")?.as_str())
-            .form(&params)
-            .send()
-            .await?;
+    /// the camera's configuration on the server, which is run to keep detection in line with it.
+    /// the version of the last one applied is kept in version_file
+    pub fn remote_config(
+        &self,
+        detection: watch::Sender<DetectionConfig>,
+        status: Arc<CameraStatus>,
+        version_file: PathBuf,
+    ) -> RemoteConfig {
+        RemoteConfig {
+            client: self.client.clone(),
+            addr: self.addr.clone(),
+            camera_id: self.camera_id.clone(),
+            secret: self.control_config.secret.clone(),
+            poll_interval: self.control_config.config_poll_interval,
+            detection,
+            status,
+            version: Arc::new(AtomicU64::new(load_version(&version_file))),
+            version_file: Some(version_file),
+        }
+    }
 
-        // later on change the webserver to alter the response code if the login fails
-        if !resp.text().await?.contains("Logout") {
-            return Err("login failed".into());
+    /// the control channel, which is run to let the server send commands to the camera.
+    /// frames are used for live view and snapshots, the detection settings and the arm mode in remote_config are
+    /// changed by the server's commands
+    pub fn controller(
+        &self,
+        frames: watch::Receiver<Option<LiveFrame>>,
+        remote_config: RemoteConfig,
+    ) -> Controller {
+        Controller {
+            client: self.client.clone(),
+            addr: self.addr.clone(),
+            camera_id: self.camera_id.clone(),
+            key_id: self.key_id.to_string(),
+            keys: self.key_cache(),
+            config: self.control_config.clone(),
+            live: self.live_view(frames),
+            detection: remote_config.detection.clone(),
+            status: remote_config.status.clone(),
+            remote_config,
         }
+    }
 
-        Ok(())
+    /// heartbeats reporting on status and the spool in spool_dir, which are run to let the server know the camera
+    /// is alive
+    pub fn heartbeat(&self, status: Arc<CameraStatus>, spool_dir: PathBuf) -> Heartbeat {
+        Heartbeat {
+            client: self.client.clone(),
+            addr: self.addr.clone(),
+            camera_id: self.camera_id.clone(),
+            config: self.heartbeat_config.clone(),
+            status,
+            spool_dir,
+            failed: 0,
+        }
+    }
+
+    pub async fn login(&self) -> Result<(), Box<dyn Error>> {
+        let result = async {
+            let mut params = HashMap::new();
+            params.insert("username", self.username);
+            params.insert("password", self.password);
+            let resp = self
+                .client
+                .post(self.addr.join("login")?.as_str())
+                .form(&params)
+                .send()
+                .await?;
+
+            // later on change the webserver to alter the response code if the login fails
+            if !resp.text().await?.contains("Logout") {
+                return Err("login failed".into());
+            }
+
+            Ok::<(), Box<dyn Error>>(())
+        }
+        .await;
+        if result.is_err() {
+            METRICS.login_failures.inc();
+        }
+        result
     }
 
     pub async fn logout(&self) -> Result<(), Box<dyn Error>> {
@@ -105,7 +265,7 @@ impl<'a> Client<'a> {
 
     #[deprecated]
     pub async fn send_and_delete(&self, filename: String) -> Result<(), Box<dyn Error>> {
-        let (key, salt) = generate_key(self.password).expect("couldnt generate keystream");
+        let (key, salt) = self.key_cache().take().await?;
 
         let file = File::options()
             .read(true)
@@ -116,10 +276,11 @@ impl<'a> Client<'a> {
         let resp = self
             .client
             .post(self.addr.join("new_video")?.as_str())
+            .header(KEY_ID_HEADER, self.key_id)
             .body(Body::wrap_stream(stream))
             .send()
             .await?;
-        println!("status: {:?}, text: {:?}", resp.status(), resp.text().await,);
+        debug!("status: {:?}, text: {:?}", resp.status(), resp.text().await,);
         fs::remove_file(&filename).await?;
         Ok(())
     }
@@ -132,7 +293,7 @@ impl<'a> Client<'a> {
         frame_count: usize,
         fps: usize,
     ) -> Result<(), Box<dyn Error>> {
-        let (key, salt) = generate_key(self.password).expect("couldnt generate keystream");
+        let (key, salt) = self.key_cache().take().await?;
         //Option<Box<dyn Stream<Item=Result<Vec<u8>, std::io::Error>>>>
         let mut enc_stream: Option<
             Pin<
@@ -177,6 +338,7 @@ impl<'a> Client<'a> {
         let resp = self
             .client
             .post(url.as_str())
+            .header(KEY_ID_HEADER, self.key_id)
             .body(Body::wrap_stream(
                 enc_stream.ok_or(std::io::Error::new(NotFound, "no file to encrypt"))?,
             ))
@@ -186,134 +348,289 @@ impl<'a> Client<'a> {
         Ok(())
     }
 
-    pub async fn send_frame_framereader(&mut self, frame: Frame) -> Result<(), Box<dyn Error>> {
-        // sending frames to main thread ->
-        // main thread calls send_frame_framereader
-        // this thread checks if there is an ongoing recording
-        // if not make a new tx rx channel
-        //      make framereader from rx channel
-        //      store framereader in self
+    /// keeps a recording encrypted in dir instead of uploading it, for when the arm mode is Local
+    pub async fn start_local_recording(
+        &self,
+        frame: Frame,
+        dir: &Path,
+    ) -> Result<LocalRecording, Box<dyn Error>> {
+        info!(
+            video = frame.video_num,
+            "starting new local recording in {}",
+            dir.display()
+        );
+        Ok(LocalRecording::start(dir, self.key_cache().take().await?, frame).await?)
+    }
+
+    /// starts uploading a new recording with frame as its first frame.
+    /// the rest of the frames are pushed onto the returned Recording, which is finished or aborted once the
+    /// motion stops. several recordings can be in flight at once, each with its own queue, key and connection.
+    /// past the configured limit a recording's frames are spilled to disk until an earlier upload is done
+    pub async fn start_recording(&self, frame: Frame) -> Result<Recording, Box<dyn Error>> {
+        // make a new upload queue
+        //      forward the receiving end of the queue to the transfer task
         //      open connection to server
-        //      spawn a new task that sends the output of encrypt_frame_reader to server
-        // if not, then send frame on tx channel
-        if self.tx.is_none() {
-            println!("Starting new transfer");
-            let (tx, rx) = channel(5);
-            self.tx = Some(tx.clone());
-            let frame_len = frame.frame_bytes.len();
-            tx.send(Ok(Bytes::from(frame.frame_bytes)))
-                .await
-                .expect("failed to send the frame butes to the receiver stream");
-
-            // start the transfer task
-            let client = self.client.clone();
-            let password = self.password.to_string();
-            let addr = self.addr.clone();
-            let transfer_task = actix_web::rt::spawn(async move {
-                let framereader = FrameReader::new(ReceiverStream::new(rx));
-                let (key, salt) = generate_key(&password).expect("couldnt generate keystream");
-                let encrypted_frame_stream = {
-                    let stream = encrypt_frame_reader(key, salt, framereader, frame_len);
-                    println!("Stream created");
-                    Box::pin(stream)
+        //      spawn a new task that sends the frames to server, encrypted one by one with encrypt_frames
+        //      (in acknowledged segments for the chunked format, or as one stream with encrypt_frame_reader
+        //      for the legacy format)
+        // further frames are pushed onto the queue, where the backpressure policy decides what happens
+        // to them if the upload cant keep up
+        let span = info_span!("recording", video = frame.video_num);
+        span.in_scope(|| info!("starting new transfer"));
+        let (mut tx, queue) = upload_queue(
+            self.upload_config.clone(),
+            self.upload_config
+                .spill_dir
+                .join(format!("{}.spill", frame.video_num)),
+        );
+        let stats = tx.stats();
+        let frame_len = frame.frame_bytes.len();
+        let video_num = frame.video_num;
+        let fps = frame.fps;
+        let metadata = Arc::new(Mutex::new(RecordingMetadata::new(
+            &self.camera_id,
+            &self.detection.borrow().hash(),
+            &frame,
+        )));
+        // sent on its own as soon as it is picked, so the server can notify about the recording before it ends
+        let mut thumbnail = ThumbnailUpload::new(
+            self.client.clone(),
+            self.addr
+                .join("upload_thumbnail/")?
+                .join(video_num.to_string().as_ref())?,
+            self.key_id.to_string(),
+            self.key_cache(),
+            video_num,
+        );
+        thumbnail.observe(&frame);
+        tx.push(frame.frame_bytes, frame.timestamp_ms).await?;
+
+        // framed uploads carry each frame's length, legacy ones are cut into chunks of the first frame's length
+        let adaptive_quality = self.upload_config.adaptive_quality;
+        let segment_frames = self.upload_config.segment_frames;
+        let (source, url) = match self.upload_config.format {
+            UploadFormat::Framed | UploadFormat::Chunked => {
+                let (frame_tx, rx) = channel(1);
+                actix_web::rt::spawn(
+                    forward_frames(queue, frame_tx, adaptive_quality, |timestamp_ms, frame| {
+                        (timestamp_ms, frame)
+                    })
+                    .instrument(span.clone()),
+                );
+                // chunked uploads put their segments under the url, so it ends in a slash
+                let (source, path) = match self.upload_config.format {
+                    UploadFormat::Framed => (
+                        FrameSource::Framed(rx),
+                        format!("upload_framed/{video_num}/{fps}"),
+                    ),
+                    _ => (
+                        FrameSource::Chunked(rx),
+                        format!("upload_chunked/{video_num}/{fps}/"),
+                    ),
                 };
-                println!("Stream pinned");
-                let url = addr
+                (source, self.addr.join(&path)?)
+            }
+            UploadFormat::Legacy => {
+                let (frame_tx, rx) = channel(1);
+                // every chunk has to stay the size of the first frame, so frames are never re-encoded
+                actix_web::rt::spawn(
+                    forward_frames(queue, frame_tx, false, |_, frame| frame)
+                        .instrument(span.clone()),
+                );
+                let url = self
+                    .addr
                     .join("upload/")?
-                    .join(&frame.video_num.to_string().to_path())?
-                    .join(&frame.fps.to_string().to_path())?
+                    .join(&video_num.to_string().to_path())?
+                    .join(&fps.to_string().to_path())?
                     .join(frame_len.to_string().as_ref())?;
-                println!("the url: {}", url.to_string());
-                if let Err(e) = async {
-                    println!("[*] opening connection");
-                    let result = client
-                        .post(url)
-                        .body(Body::wrap_stream(encrypted_frame_stream))
-                        .send()
-                        .await?;
-                    println!("Result: {}", result.text().await.unwrap());
-                    Ok::<(), Box<dyn std::error::Error>>(())
+                (FrameSource::Legacy(rx), url)
+            }
+        };
+
+        // start the transfer task
+        let client = self.client.clone();
+        let keys = self.key_cache();
+        let key_id = self.key_id.to_string();
+        let buckets = self.bandwidth.buckets_for(&self.camera_id);
+        let task_stats = stats.clone();
+        let task_metadata = metadata.clone();
+        let metadata_url = self
+            .addr
+            .join("upload_metadata/")?
+            .join(video_num.to_string().as_ref())?;
+        let upload_slots = self.upload_slots.clone();
+        // while the recording waits for a slot its frames go to disk rather than holding up capture
+        let hold = tx.hold();
+        let transfer_task = actix_web::rt::spawn(
+            async move {
+                if upload_slots.available_permits() == 0 {
+                    info!("waiting for an earlier upload to finish");
                 }
-                .await
-                {
-                    eprintln!("[ERROR] Spawned task failed: {}", e);
+                let _upload_slot = upload_slots.acquire_owned().await?;
+                drop(hold);
+                let (key, salt) = keys.take().await?;
+                debug!(%url, "opening connection");
+                let result = match source {
+                    FrameSource::Framed(rx) => {
+                        let stream = encrypt_frames((key, salt), ReceiverStream::new(rx));
+                        let stream = limit_stream(stream, buckets);
+                        post_stream(client.clone(), url, key_id.clone(), stream).await
+                    }
+                    FrameSource::Chunked(rx) => {
+                        let upload =
+                            ChunkedUpload::new(client.clone(), url, key_id.clone(), buckets);
+                        upload_chunked(upload, (key, salt), ReceiverStream::new(rx), segment_frames)
+                            .await
+                    }
+                    FrameSource::Legacy(rx) => {
+                        let framereader = FrameReader::new(ReceiverStream::new(rx));
+                        let stream = encrypt_frame_reader(key, salt, framereader, frame_len);
+                        let stream = limit_stream(stream, buckets);
+                        post_stream(client.clone(), url, key_id.clone(), stream).await
+                    }
+                };
+                info!("{task_stats}");
+                if result.is_err() {
+                    METRICS.upload_failures.inc();
                 }
-                Ok::<(), Box<dyn std::error::Error>>(())
-            });
-            self.transfer_task = Some(transfer_task);
-        } else {
-            let result = self
-                .tx
-                .clone()
-                .unwrap()
-                .send(Ok(Bytes::from(frame.frame_bytes)))
-                .await;
-            if result.is_err() {
-                eprintln!(
-                    "[ERROR] sending over tx: {}",
-                    result.err().unwrap().to_string()
-                );
+
+                // the metadata is only complete once every frame has gone through the queue
+                if result.is_ok() {
+                    let mut metadata = task_metadata.lock().unwrap().clone();
+                    metadata.finish(&task_stats);
+                    let sent = async {
+                        let key_pair = keys.take().await?;
+                        send_metadata(&client, metadata_url, &key_id, key_pair, &metadata).await
+                    };
+                    if let Err(e) = sent.await {
+                        warn!("sending metadata: {e}");
+                    }
+                }
+                result
             }
-        }
+            .instrument(span),
+        );
+        Ok(Recording::new(
+            video_num,
+            tx,
+            stats,
+            metadata,
+            Some(thumbnail),
+            transfer_task,
+        ))
+    }
 
+    /// sends frame as part of the ongoing recording, starting one if there isnt one.
+    /// the recording is finished once a frame marked as the end is sent
+    #[deprecated(note = "use start_recording and the returned Recording")]
+    pub async fn send_frame_framereader(&mut self, frame: Frame) -> Result<(), Box<dyn Error>> {
+        let is_last_frame = frame.end;
+        match self.recording.as_mut() {
+            Some(recording) => {
+                if let Err(e) = recording.push_frame(frame).await {
+                    warn!("queueing frame for upload: {}", e);
+                }
+            }
+            None => self.recording = Some(self.start_recording(frame).await?),
+        }
+        if is_last_frame {
+            if let Some(recording) = self.recording.take() {
+                info!("result: {}", recording.finish().await?);
+            }
+        }
         Ok(())
     }
+}
+
+/// where a recording's transfer task gets its frames from, in the shape its upload format needs
+enum FrameSource {
+    Framed(Receiver<Result<(u64, Bytes), std::io::Error>>),
+    Chunked(Receiver<Result<(u64, Bytes), std::io::Error>>),
+    Legacy(Receiver<Result<Bytes, std::io::Error>>),
+}
 
-    // #[deprecated]
-    // pub async fn send_frame_ws(&mut self, frame: Frame) -> Result<(), Box<dyn Error>> {
-    //     // need new salt each time
-    //     let (key, salt) = generate_key(self.password).expect("couldnt generate keystream");
-    //     let mut stream = Box::pin(encrypt_stream_frame(
-    //         key,
-    //         salt,
-    //         Cursor::new(frame.frame_bytes),
-    //     ));
-
-    //     // create the decrypted frame
-    //     let mut encrypted_frame: Vec<u8> = Vec::new();
-    //     while let Some(Ok(chunk)) = stream.next().await {
-    //         encrypted_frame.extend(chunk);
-    //     }
-
-    //     println!("got here, abt to check websocket connection private member");
-    //     if self.websocket_connection.is_none() {
-    //         let url = self
-    //             .addr
-    //             .join("new_video_stream/")?
-    //             .join(&frame.video_num.to_string().to_path())?
-    //             .join(&frame.fps.to_string())?;
-    //         let connection = self
-    //             .client
-    //             .get(url)
-    //             .upgrade()
-    //             .send()
-    //             .await?
-    //             .into_websocket()
-    //             .await?;
-    //         self.websocket_connection = Some(connection);
-    //     }
-    //     println!("got here");
-    //     if let Some(ws) = &mut self.websocket_connection {
-    //         println!("sending frame over websocket connection");
-    //         let (mut tx, mut rx) = ws.split();
-    //         security_cam_common::futures::future::join(
-    //             async move {
-    //                 tx.send(reqwest_websocket::Message::Binary(encrypted_frame))
-    //                     .await
-    //                     .expect("shit");
-    //             },
-    //             async move {
-    //                 while let Some(message) = rx.try_next().await.unwrap() {
-    //                     if let reqwest_websocket::Message::Text(text) = message {
-    //                         println!("received: {text}");
-    //                     }
-    //                 }
-    //             },
-    //         )
-    //         .await;
-    //     }
-    //     Ok(())
-    // }
+/// sends a recording's metadata, encrypted with key_pair
+async fn send_metadata(
+    client: &reqwest::Client,
+    url: Url,
+    key_id: &str,
+    key_pair: KeyPair,
+    metadata: &RecordingMetadata,
+) -> Result<(), Box<dyn Error>> {
+    client
+        .post(url)
+        .header(KEY_ID_HEADER, key_id)
+        .body(metadata.encrypt(key_pair)?)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// uploads stream as the body of a single POST, returning the server's response
+async fn post_stream<S>(
+    client: reqwest::Client,
+    url: Url,
+    key_id: String,
+    stream: S,
+) -> Result<String, Box<dyn Error>>
+where
+    S: Stream<Item = Result<Vec<u8>, std::io::Error>> + Send + 'static,
+{
+    let stream = stream.inspect(|chunk| {
+        if let Ok(chunk) = chunk {
+            METRICS.upload_bytes.add(chunk.len() as u64);
+        }
+    });
+    let response = client
+        .post(url)
+        .header(KEY_ID_HEADER, key_id)
+        .body(Body::wrap_stream(stream))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(response.text().await?)
+}
+
+/// forwards whatever comes out of queue into tx, shaped by shape, until the queue is finished or the upload stops
+/// taking frames. how fast the channel takes frames is how fast the upload is going, so this is also where
+/// frames get re-encoded at a lower quality, and some left out, if adaptive quality is on and the queue is backing up
+async fn forward_frames<T>(
+    mut queue: UploadReceiver,
+    tx: Sender<Result<T, std::io::Error>>,
+    adaptive_quality: bool,
+    shape: fn(u64, Bytes) -> T,
+) {
+    let mut adaptive = AdaptiveQuality::default();
+    let mut index = 0;
+    while let Some(frame) = queue.pop().await {
+        index += 1;
+        if let Some(quality) = adaptive.quality().filter(|_| adaptive_quality) {
+            if !quality.keeps(index) {
+                queue.stats().dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        }
+        let frame = match (frame, adaptive.quality()) {
+            (Ok((timestamp_ms, frame)), Some(quality)) if adaptive_quality => {
+                let original = frame.clone();
+                match tokio::task::spawn_blocking(move || reencode(&frame, quality)).await {
+                    Ok(Ok(reencoded)) => Ok((timestamp_ms, Bytes::from(reencoded))),
+                    _ => Ok((timestamp_ms, original)),
+                }
+            }
+            (frame, _) => frame,
+        };
+        let sent_bytes = frame.as_ref().map(|(_, frame)| frame.len()).unwrap_or(0);
+        let frame = frame.map(|(timestamp_ms, frame)| shape(timestamp_ms, frame));
+        if tx.send(frame).await.is_err() {
+            break;
+        }
+        if adaptive_quality {
+            adaptive.observe(sent_bytes, queue.backlog(), queue.capacity());
+        }
+    }
+    *queue.stats().quality_changes.lock().unwrap() = adaptive.changes;
 }
 
 trait ToPathSegment {
@@ -329,7 +646,63 @@ impl ToPathSegment for String {
 
 #[cfg(test)]
 mod tests {
-    use security_cam_common::shuttle_runtime::tokio;
+    use super::*;
+    use security_cam_common::shuttle_runtime::tokio::net::TcpListener;
+
+    fn frame(video_num: usize, i: u64) -> Frame {
+        Frame {
+            video_num,
+            frame_bytes: vec![i as u8; 100],
+            fps: 25,
+            end: false,
+            timestamp_ms: i * 40,
+            motion_score: 0,
+            bounding_boxes: Vec::new(),
+            zones: Vec::new(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_hung_upload_doesnt_hold_up_next_recording() {
+        // takes connections and never answers them
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let spill_dir =
+            std::env::temp_dir().join(format!("security-cam-slots-{}", std::process::id()));
+        std::fs::create_dir_all(&spill_dir).unwrap();
+        let config = ClientConfig {
+            upload: UploadConfig {
+                max_concurrent: 1,
+                spill_dir: spill_dir.clone(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut client = Client::with_config(&addr, "admin", "pass", &config)
+            .await
+            .unwrap();
+        client.set_encryption_key([7; 32], "default");
+
+        let hung = client.start_recording(frame(1, 0)).await.unwrap();
+        let mut next = client.start_recording(frame(2, 0)).await.unwrap();
+        for i in 1..50 {
+            tokio::time::timeout(Duration::from_secs(5), next.push_frame(frame(2, i)))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert!(next.stats().spilled.load(Ordering::Relaxed) > 0);
+
+        hung.abort();
+        next.abort();
+        let _ = std::fs::remove_dir_all(&spill_dir);
+    }
 
     /// only run this test while the server is active
     #[tokio::test]
@@ -0,0 +1,204 @@
+use actix_web::web::Bytes;
+use image::codecs::jpeg::JpegEncoder;
+use image::{ImageFormat, Rgb, RgbImage};
+use imageproc::drawing::{draw_filled_rect_mut, draw_hollow_rect_mut};
+use imageproc::rect::Rect;
+
+use crate::motiondetection::{BoundingBox, DetectionConfig, PreviewFrame};
+
+/// separates the jpegs in the multipart stream
+pub const BOUNDARY: &str = "preview-frame";
+
+const PREVIEW_QUALITY: u8 = 80;
+
+/// changed pixels are tinted red, masked areas dimmed and outlined blue, zones outlined yellow and motion boxes
+/// outlined green
+const MOTION_COLOUR: Rgb<u8> = Rgb([255, 0, 0]);
+const MASK_COLOUR: Rgb<u8> = Rgb([0, 128, 255]);
+const ZONE_COLOUR: Rgb<u8> = Rgb([255, 220, 0]);
+const BOX_COLOUR: Rgb<u8> = Rgb([0, 255, 0]);
+
+/// the score is drawn red once it is over min_score
+const SCORE_COLOUR: Rgb<u8> = Rgb([255, 255, 255]);
+const SCORE_MOTION_COLOUR: Rgb<u8> = Rgb([255, 64, 64]);
+
+/// 3x5 pixel digits and a slash for writing `<score>/<min score>`, one row per byte with the leftmost pixel in
+/// the highest of the low 3 bits
+const GLYPHS: [[u8; 5]; 11] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b001, 0b001, 0b010, 0b100, 0b100],
+];
+
+/// each glyph pixel is drawn as a square this big
+const GLYPH_SCALE: u32 = 4;
+
+/// draws what motion detection saw on top of frame: the changed pixels, motion boxes, masks and zones from config,
+/// and the motion score against config's min_score in the top left
+pub fn render_preview(
+    frame: &PreviewFrame,
+    config: &DetectionConfig,
+) -> Result<Vec<u8>, image::ImageError> {
+    let mut image = image::load_from_memory_with_format(&frame.jpeg, ImageFormat::Jpeg)?.to_rgb8();
+    for (x, y, pixel) in frame.motion_mask.enumerate_pixels() {
+        if pixel[0] != 0 && x < image.width() && y < image.height() {
+            let Rgb([_, g, b]) = *image.get_pixel(x, y);
+            image.put_pixel(x, y, Rgb([MOTION_COLOUR[0], g / 2, b / 2]));
+        }
+    }
+    for mask in &config.masks {
+        dim(&mut image, mask);
+        outline(&mut image, mask, MASK_COLOUR);
+    }
+    for zone in &config.zones {
+        outline(&mut image, &zone.area, ZONE_COLOUR);
+    }
+    for bounding_box in &frame.bounding_boxes {
+        outline(&mut image, bounding_box, BOX_COLOUR);
+    }
+    let colour = if frame.motion_score > config.min_score {
+        SCORE_MOTION_COLOUR
+    } else {
+        SCORE_COLOUR
+    };
+    draw_text(
+        &mut image,
+        &format!("{}/{}", frame.motion_score, config.min_score),
+        colour,
+    );
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, PREVIEW_QUALITY).encode_image(&image)?;
+    Ok(jpeg)
+}
+
+/// one part of a `multipart/x-mixed-replace` stream
+pub fn mjpeg_part(jpeg: &[u8]) -> Bytes {
+    let mut part = format!(
+        "--{BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        jpeg.len()
+    )
+    .into_bytes();
+    part.extend_from_slice(jpeg);
+    part.extend_from_slice(b"\r\n");
+    Bytes::from(part)
+}
+
+fn rect(area: &BoundingBox) -> Option<Rect> {
+    (area.width > 0 && area.height > 0)
+        .then(|| Rect::at(area.x as i32, area.y as i32).of_size(area.width, area.height))
+}
+
+fn outline(image: &mut RgbImage, area: &BoundingBox, colour: Rgb<u8>) {
+    if let Some(rect) = rect(area) {
+        draw_hollow_rect_mut(image, rect, colour);
+    }
+}
+
+fn dim(image: &mut RgbImage, area: &BoundingBox) {
+    let right = area.x.saturating_add(area.width).min(image.width());
+    let bottom = area.y.saturating_add(area.height).min(image.height());
+    for y in area.y.min(bottom)..bottom {
+        for x in area.x.min(right)..right {
+            let pixel = image.get_pixel_mut(x, y);
+            pixel.0 = pixel.0.map(|channel| channel / 3);
+        }
+    }
+}
+
+/// writes text made of digits and slashes on a black background in the top left corner
+fn draw_text(image: &mut RgbImage, text: &str, colour: Rgb<u8>) {
+    let advance = 4 * GLYPH_SCALE;
+    let width = text.len() as u32 * advance + GLYPH_SCALE;
+    let height = 7 * GLYPH_SCALE;
+    draw_filled_rect_mut(image, Rect::at(0, 0).of_size(width, height), Rgb([0, 0, 0]));
+    for (i, c) in text.chars().enumerate() {
+        let glyph = match c {
+            '0'..='9' => GLYPHS[c as usize - '0' as usize],
+            '/' => GLYPHS[10],
+            _ => continue,
+        };
+        let left = GLYPH_SCALE + i as u32 * advance;
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) != 0 {
+                    let x = left + column * GLYPH_SCALE;
+                    let y = GLYPH_SCALE + row as u32 * GLYPH_SCALE;
+                    draw_filled_rect_mut(
+                        image,
+                        Rect::at(x as i32, y as i32).of_size(GLYPH_SCALE, GLYPH_SCALE),
+                        colour,
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    fn jpeg(image: &RgbImage) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 100)
+            .encode_image(image)
+            .unwrap();
+        jpeg
+    }
+
+    #[test]
+    fn test_render_preview() {
+        let mut motion_mask = GrayImage::new(64, 48);
+        for y in 30..40 {
+            for x in 40..50 {
+                motion_mask.put_pixel(x, y, Luma([255]));
+            }
+        }
+        let frame = PreviewFrame {
+            timestamp_ms: 1,
+            jpeg: jpeg(&RgbImage::from_pixel(64, 48, Rgb([128, 128, 128]))),
+            motion_score: 100,
+            motion_mask,
+            bounding_boxes: vec!["40,30,10,10".parse().unwrap()],
+        };
+        let config = DetectionConfig {
+            masks: vec!["0,40,64,8".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let preview = render_preview(&frame, &config).unwrap();
+        let preview = image::load_from_memory_with_format(&preview, ImageFormat::Jpeg)
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(preview.dimensions(), (64, 48));
+        let Rgb([r, g, b]) = *preview.get_pixel(45, 35);
+        assert!(
+            r > 200 && g < 100 && b < 100,
+            "motion isnt red: {r} {g} {b}"
+        );
+        let Rgb([r, g, b]) = *preview.get_pixel(20, 44);
+        assert!(r < 70 && g < 70 && b < 70, "mask isnt dimmed: {r} {g} {b}");
+        // the score is written over a black box in the corner
+        let Rgb([r, g, b]) = *preview.get_pixel(1, 1);
+        assert!(r < 40 && g < 40 && b < 40);
+    }
+
+    #[test]
+    fn test_mjpeg_part() {
+        let part = mjpeg_part(&[0xff, 0xd8, 0xff, 0xd9]);
+        let header =
+            format!("--{BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: 4\r\n\r\n");
+        assert!(part.starts_with(header.as_bytes()));
+        assert!(part.ends_with(&[0xff, 0xd8, 0xff, 0xd9, b'\r', b'\n']));
+    }
+}
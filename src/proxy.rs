@@ -0,0 +1,339 @@
+use reqwest::{ClientBuilder, NoProxy, Proxy};
+use std::error::Error;
+
+/// environment variable holding the proxy url, eg. http://proxy:3128 or socks5h://proxy:1080
+pub const PROXY_VAR: &str = "SECURITY_CAM_PROXY";
+
+/// environment variables holding the proxy credentials, if they arent part of the proxy url
+pub const PROXY_USERNAME_VAR: &str = "SECURITY_CAM_PROXY_USERNAME";
+pub const PROXY_PASSWORD_VAR: &str = "SECURITY_CAM_PROXY_PASSWORD";
+
+/// environment variable holding comma separated hosts, domains and ip ranges that are reached directly
+pub const NO_PROXY_VAR: &str = "SECURITY_CAM_NO_PROXY";
+
+/// the standard proxy variables, used when SECURITY_CAM_PROXY isnt set. like curl, HTTP_PROXY is only used for
+/// http:// urls and HTTPS_PROXY only for https:// ones, with ALL_PROXY for whichever of them isnt set
+const STANDARD_HTTP_PROXY_VARS: [&str; 2] = ["HTTP_PROXY", "http_proxy"];
+const STANDARD_HTTPS_PROXY_VARS: [&str; 2] = ["HTTPS_PROXY", "https_proxy"];
+const STANDARD_ALL_PROXY_VARS: [&str; 2] = ["ALL_PROXY", "all_proxy"];
+const STANDARD_NO_PROXY_VARS: [&str; 2] = ["NO_PROXY", "no_proxy"];
+
+/// an http(s) or socks5 proxy all requests to the server go through
+#[derive(Default, Clone, Debug)]
+pub struct ProxyConfig {
+    /// http://, https://, socks5:// or socks5h:// url of the proxy, None to connect directly
+    pub url: Option<String>,
+
+    /// proxies used instead of url for http:// and https:// urls only, eg. from HTTP_PROXY and HTTPS_PROXY
+    pub http_url: Option<String>,
+    pub https_url: Option<String>,
+
+    /// basic auth (or socks5 username/password auth) for the proxy
+    pub username: Option<String>,
+    pub password: Option<String>,
+
+    /// comma separated list in the same format as NO_PROXY
+    pub no_proxy: Option<String>,
+}
+
+impl ProxyConfig {
+    /// reads the SECURITY_CAM_PROXY* variables, falling back to the standard HTTPS_PROXY, HTTP_PROXY, ALL_PROXY
+    /// and NO_PROXY variables
+    pub fn from_env() -> Self {
+        let (url, http_url, https_url) = match std::env::var(PROXY_VAR) {
+            Ok(url) => (Some(url), None, None),
+            Err(_) => (
+                first_var(&STANDARD_ALL_PROXY_VARS),
+                first_var(&STANDARD_HTTP_PROXY_VARS),
+                first_var(&STANDARD_HTTPS_PROXY_VARS),
+            ),
+        };
+        let no_proxy = std::env::var(NO_PROXY_VAR)
+            .ok()
+            .or_else(|| first_var(&STANDARD_NO_PROXY_VARS));
+        let password = std::env::var(PROXY_PASSWORD_VAR).ok();
+        std::env::remove_var(PROXY_PASSWORD_VAR);
+        let set = |url: Option<String>| url.filter(|url| !url.trim().is_empty());
+        ProxyConfig {
+            url: set(url),
+            http_url: set(http_url),
+            https_url: set(https_url),
+            username: std::env::var(PROXY_USERNAME_VAR).ok(),
+            password,
+            no_proxy,
+        }
+    }
+
+    /// sets up the proxies on builder. reqwest's own detection of proxy variables is turned off so that only
+    /// what from_env found is used
+    pub fn apply(&self, builder: ClientBuilder) -> Result<ClientBuilder, Box<dyn Error>> {
+        let mut builder = builder.no_proxy();
+        // reqwest uses the first proxy that matches, so the scheme specific ones go first
+        if let Some(url) = &self.http_url {
+            builder = builder.proxy(self.finish(Proxy::http(url.trim()), url)?);
+        }
+        if let Some(url) = &self.https_url {
+            builder = builder.proxy(self.finish(Proxy::https(url.trim()), url)?);
+        }
+        if let Some(url) = &self.url {
+            builder = builder.proxy(self.finish(Proxy::all(url.trim()), url)?);
+        }
+        Ok(builder)
+    }
+
+    /// adds no_proxy and the credentials to a proxy made from url
+    fn finish(&self, proxy: reqwest::Result<Proxy>, url: &str) -> Result<Proxy, Box<dyn Error>> {
+        let mut proxy = proxy
+            .map_err(|e| format!("invalid proxy url {url}: {e}"))?
+            .no_proxy(self.no_proxy.as_deref().and_then(NoProxy::from_string));
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => proxy = proxy.basic_auth(username, password),
+            (Some(username), None) => proxy = proxy.basic_auth(username, ""),
+            (None, Some(_)) => {
+                return Err(format!("{PROXY_PASSWORD_VAR} is set without {PROXY_USERNAME_VAR}").into())
+            }
+            (None, None) => {}
+        }
+        Ok(proxy)
+    }
+}
+
+fn first_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClientConfig;
+    use crate::networking::Client;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use security_cam_common::shuttle_runtime::tokio;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// stand in for both a forward proxy and the server, answers every request with a logged in page
+    /// and records the head of each request it gets
+    async fn spawn_stand_in() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                if let Some(head) = answer_request(&mut stream).await {
+                    recorded.lock().unwrap().push(head);
+                }
+            }
+        });
+        (addr, requests)
+    }
+
+    /// stand in for a socks5 proxy that only takes username/password auth, with the server behind it.
+    /// records the credentials and target of each connection, and the head of the request sent through it
+    async fn spawn_socks_stand_in() -> (SocketAddr, Arc<Mutex<Vec<(String, String, String)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let recorded = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                if let Ok(Some((credentials, target))) = socks_handshake(&mut stream).await {
+                    if let Some(head) = answer_request(&mut stream).await {
+                        recorded.lock().unwrap().push((credentials, target, head));
+                    }
+                }
+            }
+        });
+        (addr, connections)
+    }
+
+    /// the server side of a socks5 handshake with username/password auth, returning `username:password`
+    /// and the `host:port` asked for, or None if the client didnt offer username/password auth
+    async fn socks_handshake(stream: &mut TcpStream) -> std::io::Result<Option<(String, String)>> {
+        let mut header = [0u8; 2];
+        stream.read_exact(&mut header).await?;
+        let mut methods = vec![0u8; header[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        if header[0] != 5 || !methods.contains(&2) {
+            stream.write_all(&[5, 0xff]).await?;
+            return Ok(None);
+        }
+        stream.write_all(&[5, 2]).await?;
+
+        let mut version = [0u8; 1];
+        stream.read_exact(&mut version).await?;
+        let username = read_socks_string(stream).await?;
+        let password = read_socks_string(stream).await?;
+        stream.write_all(&[1, 0]).await?;
+
+        // connect request, the target is a domain name since the url is socks5h
+        let mut request = [0u8; 4];
+        stream.read_exact(&mut request).await?;
+        let host = match request[3] {
+            3 => read_socks_string(stream).await?,
+            1 => {
+                let mut ip = [0u8; 4];
+                stream.read_exact(&mut ip).await?;
+                std::net::Ipv4Addr::from(ip).to_string()
+            }
+            _ => return Ok(None),
+        };
+        let port = stream.read_u16().await?;
+        stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await?;
+        let credentials = format!("{username}:{password}");
+        Ok(Some((credentials, format!("{host}:{port}"))))
+    }
+
+    async fn read_socks_string(stream: &mut TcpStream) -> std::io::Result<String> {
+        let len = stream.read_u8().await?;
+        let mut bytes = vec![0u8; len as usize];
+        stream.read_exact(&mut bytes).await?;
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    /// reads one http request off stream and answers it with a logged in page, returning the request's head
+    async fn answer_request(stream: &mut TcpStream) -> Option<String> {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        let head_len = loop {
+            if let Some(i) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                break i + 4;
+            }
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        };
+        let head = String::from_utf8_lossy(&request[..head_len]).to_string();
+        let content_length = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse::<usize>().ok())?
+            })
+            .unwrap_or(0);
+        while request.len() < head_len + content_length {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        let _ = stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 6\r\nconnection: close\r\n\r\nLogout")
+            .await;
+        let _ = stream.shutdown().await;
+        Some(head)
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_with_auth() {
+        let (proxy_addr, requests) = spawn_stand_in().await;
+        let config = ClientConfig {
+            proxy: ProxyConfig {
+                url: Some(format!("http://{proxy_addr}")),
+                username: Some("camera".to_string()),
+                password: Some("secret".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let client = Client::with_config("http://camera-server.invalid/", "admin", "pass", &config)
+            .await
+            .unwrap();
+        client.login().await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("POST http://camera-server.invalid/login HTTP/1.1"));
+        let auth = format!("proxy-authorization: Basic {}", STANDARD.encode("camera:secret"));
+        assert!(requests[0].to_ascii_lowercase().contains(&auth.to_ascii_lowercase()));
+    }
+
+    #[tokio::test]
+    async fn test_no_proxy_bypasses_proxy() {
+        let (proxy_addr, proxy_requests) = spawn_stand_in().await;
+        let (server_addr, server_requests) = spawn_stand_in().await;
+        let config = ClientConfig {
+            proxy: ProxyConfig {
+                url: Some(format!("http://{proxy_addr}")),
+                no_proxy: Some("example.com,127.0.0.1".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let addr = format!("http://{server_addr}/");
+        let client = Client::with_config(&addr, "admin", "pass", &config)
+            .await
+            .unwrap();
+        client.login().await.unwrap();
+
+        assert!(proxy_requests.lock().unwrap().is_empty());
+        assert_eq!(server_requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy_only_for_http() {
+        let (proxy_addr, requests) = spawn_stand_in().await;
+        let config = ClientConfig {
+            proxy: ProxyConfig {
+                http_url: Some(format!("http://{proxy_addr}")),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let client = Client::with_config("http://camera-server.invalid/", "admin", "pass", &config)
+            .await
+            .unwrap();
+        client.login().await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // https goes straight to the server, which isnt listening
+        let client = Client::with_config("https://127.0.0.1:1/", "admin", "pass", &config)
+            .await
+            .unwrap();
+        client.login().await.unwrap_err();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_proxy_with_auth() {
+        let (proxy_addr, connections) = spawn_socks_stand_in().await;
+        let config = ClientConfig {
+            proxy: ProxyConfig {
+                url: Some(format!("socks5h://{proxy_addr}")),
+                username: Some("camera".to_string()),
+                password: Some("secret".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let client = Client::with_config("http://camera-server.invalid/", "admin", "pass", &config)
+            .await
+            .unwrap();
+        client.login().await.unwrap();
+
+        let connections = connections.lock().unwrap();
+        assert_eq!(connections.len(), 1);
+        let (credentials, target, head) = &connections[0];
+        assert_eq!(credentials, "camera:secret");
+        assert_eq!(target, "camera-server.invalid:80");
+        assert!(head.starts_with("POST /login HTTP/1.1"));
+    }
+
+    #[test]
+    fn test_password_without_username_refused() {
+        let config = ProxyConfig {
+            url: Some("socks5h://127.0.0.1:1080".to_string()),
+            password: Some("secret".to_string()),
+            ..Default::default()
+        };
+        config.apply(reqwest::Client::builder()).unwrap_err();
+    }
+}
@@ -0,0 +1,166 @@
+use actix_web::rt::task::JoinHandle;
+use security_cam_common::shuttle_runtime::tokio::fs::File;
+use security_cam_common::shuttle_runtime::tokio::io::AsyncWriteExt;
+use std::error::Error;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::framing::FrameEncryptor;
+use crate::keys::KeyPair;
+use crate::metadata::RecordingMetadata;
+use crate::motiondetection::Frame;
+use crate::thumbnail::ThumbnailUpload;
+use crate::upload_queue::{QueueSpool, UploadSender, UploadStats};
+
+/// a recording that is being uploaded, returned by Client::start_recording.
+/// dropping it without calling finish or abort finishes it
+pub struct Recording {
+    video_num: usize,
+    tx: UploadSender,
+    stats: Arc<UploadStats>,
+
+    /// shared with the transfer task, which sends it once the frames are uploaded
+    metadata: Arc<Mutex<RecordingMetadata>>,
+
+    /// None once the thumbnail has been sent
+    thumbnail: Option<ThumbnailUpload>,
+    transfer_task: JoinHandle<Result<String, Box<dyn Error>>>,
+}
+
+impl Recording {
+    pub(crate) fn new(
+        video_num: usize,
+        tx: UploadSender,
+        stats: Arc<UploadStats>,
+        metadata: Arc<Mutex<RecordingMetadata>>,
+        thumbnail: Option<ThumbnailUpload>,
+        transfer_task: JoinHandle<Result<String, Box<dyn Error>>>,
+    ) -> Self {
+        Recording {
+            video_num,
+            tx,
+            stats,
+            metadata,
+            thumbnail,
+            transfer_task,
+        }
+    }
+
+    pub fn video_num(&self) -> usize {
+        self.video_num
+    }
+
+    /// frame, drop and spill counts so far
+    pub fn stats(&self) -> Arc<UploadStats> {
+        self.stats.clone()
+    }
+
+    /// for keeping the frames that havent been uploaded yet, eg. when stopping before the upload is done
+    pub fn spool(&self) -> QueueSpool {
+        self.tx.spool()
+    }
+
+    /// queues the next frame of the recording for upload
+    pub async fn push_frame(&mut self, frame: Frame) -> Result<(), std::io::Error> {
+        self.metadata.lock().unwrap().observe(&frame);
+        if let Some(thumbnail) = self.thumbnail.as_mut() {
+            if thumbnail.observe(&frame) {
+                self.thumbnail = None;
+            }
+        }
+        self.tx.push(frame.frame_bytes, frame.timestamp_ms).await
+    }
+
+    /// marks the end of the recording. the upload carries on with whatever is still queued,
+    /// and the returned future resolves to the server's response once it is done
+    pub fn finish(self) -> UploadCompletion {
+        let Recording {
+            tx,
+            thumbnail,
+            transfer_task,
+            ..
+        } = self;
+        // a recording shorter than the thumbnail window still gets one
+        if let Some(mut thumbnail) = thumbnail {
+            thumbnail.send();
+        }
+        drop(tx);
+        UploadCompletion { transfer_task }
+    }
+
+    /// stops the upload straight away, throwing away anything still queued
+    pub fn abort(self) {
+        self.transfer_task.abort();
+    }
+}
+
+/// resolves to the server's response to a finished recording's upload
+pub struct UploadCompletion {
+    transfer_task: JoinHandle<Result<String, Box<dyn Error>>>,
+}
+
+impl Future for UploadCompletion {
+    type Output = Result<String, Box<dyn Error>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.transfer_task)
+            .poll(cx)
+            .map(|result| match result {
+                Ok(result) => result,
+                Err(e) => Err(format!("upload task failed: {e}").into()),
+            })
+    }
+}
+
+/// a recording kept on the camera instead of being uploaded, for when the arm mode is Local.
+/// written as a framed upload (see framing) to `<dir>/<video>-<start ms>.scf`, so it can be uploaded or decrypted
+/// later like any other
+pub struct LocalRecording {
+    video_num: usize,
+    file: File,
+    path: PathBuf,
+    encryptor: FrameEncryptor,
+}
+
+impl LocalRecording {
+    pub async fn start(
+        dir: &Path,
+        key_pair: KeyPair,
+        first: Frame,
+    ) -> Result<Self, std::io::Error> {
+        let (key, salt) = key_pair;
+        let path = dir.join(format!("{}-{}.scf", first.video_num, first.timestamp_ms));
+        let mut file = File::create(&path).await?;
+        file.write_all(&FrameEncryptor::header(salt.as_str()))
+            .await?;
+        let mut recording = LocalRecording {
+            video_num: first.video_num,
+            file,
+            path,
+            encryptor: FrameEncryptor::new(&key),
+        };
+        recording.push_frame(first).await?;
+        Ok(recording)
+    }
+
+    pub fn video_num(&self) -> usize {
+        self.video_num
+    }
+
+    pub async fn push_frame(&mut self, frame: Frame) -> Result<(), std::io::Error> {
+        let record = self
+            .encryptor
+            .encrypt(frame.timestamp_ms, &frame.frame_bytes)?;
+        self.file.write_all(&record).await
+    }
+
+    /// flushes the recording to disk, returning where it is
+    pub async fn finish(mut self) -> Result<PathBuf, std::io::Error> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        Ok(self.path)
+    }
+}
@@ -0,0 +1,315 @@
+use reqwest::{StatusCode, Url};
+use security_cam_common::shuttle_runtime::tokio;
+use security_cam_common::shuttle_runtime::tokio::sync::watch;
+use serde::Deserialize;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::arming::ArmScheduleDocument;
+use crate::control::{mac_matches, CONTROL_SECRET_VAR};
+use crate::health::CameraStatus;
+use crate::motiondetection::{BoundingBox, DetectionConfig, Zone};
+
+/// a camera's configuration as it comes from the server.
+/// body is a CameraConfig as json and mac is hex HMAC-SHA256 of `<version>.<body>` with the control secret
+#[derive(Debug, Deserialize)]
+struct SignedConfig {
+    version: u64,
+    body: String,
+    #[serde(default)]
+    mac: Option<String>,
+}
+
+/// settings the server can change on a running camera, leaving one out keeps what the camera has
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct CameraConfig {
+    pub detection: Option<DetectionPatch>,
+    pub arming: Option<ArmScheduleDocument>,
+}
+
+/// changes to the detection settings, each field left out keeps what the camera has, eg. from SECURITY_CAM_*
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct DetectionPatch {
+    pub threshold: Option<u8>,
+    pub min_score: Option<u32>,
+    pub zones: Option<Vec<Zone>>,
+    pub masks: Option<Vec<BoundingBox>>,
+}
+
+impl DetectionPatch {
+    pub fn apply_to(self, config: &mut DetectionConfig) {
+        if let Some(threshold) = self.threshold {
+            config.threshold = threshold;
+        }
+        if let Some(min_score) = self.min_score {
+            config.min_score = min_score;
+        }
+        if let Some(zones) = self.zones {
+            config.zones = zones;
+        }
+        if let Some(masks) = self.masks {
+            config.masks = masks;
+        }
+    }
+}
+
+/// checks a configuration document from the server and takes the config out of it, refusing it unless it is signed
+/// with secret. returns None if it is no newer than current_version, the version of the last one applied
+pub fn open_config(
+    text: &str,
+    secret: &str,
+    current_version: u64,
+) -> Result<Option<(u64, CameraConfig)>, Box<dyn Error>> {
+    let signed: SignedConfig = serde_json::from_str(text)?;
+    let signed_text = format!("{}.{}", signed.version, signed.body);
+    if !mac_matches(secret, &signed_text, signed.mac.as_deref()) {
+        return Err(format!("configuration version {} has a bad mac", signed.version).into());
+    }
+    if signed.version <= current_version {
+        return Ok(None);
+    }
+    Ok(Some((signed.version, serde_json::from_str(&signed.body)?)))
+}
+
+/// keeps the camera's settings in line with `config/<camera>` on the server, fetched at startup, every
+/// poll interval and whenever the control channel asks for it. changes are applied to the running MotionDetector
+/// through its config_handle and to the arm schedule in status, without restarting capture
+#[derive(Clone)]
+pub struct RemoteConfig {
+    pub(crate) client: reqwest::Client,
+    pub(crate) addr: Url,
+    pub(crate) camera_id: String,
+    pub(crate) secret: Option<String>,
+    pub(crate) poll_interval: Duration,
+    pub(crate) detection: watch::Sender<DetectionConfig>,
+    pub(crate) status: Arc<CameraStatus>,
+
+    /// version of the last document applied, 0 before the first one
+    pub(crate) version: Arc<AtomicU64>,
+
+    /// where version is saved, so an older document cant be replayed after a restart
+    pub(crate) version_file: Option<PathBuf>,
+}
+
+impl RemoteConfig {
+    /// fetches the configuration every poll interval, starting one interval from now. without a control secret
+    /// nothing from the server could be trusted, so it isnt fetched at all
+    pub async fn run(self) {
+        if self.secret.is_none() {
+            warn!(
+                "{CONTROL_SECRET_VAR} isnt set, not fetching camera configuration from the server"
+            );
+            return;
+        }
+        let mut interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.poll_interval,
+            self.poll_interval,
+        );
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.reload().await {
+                warn!("fetching camera configuration: {e}");
+            }
+        }
+    }
+
+    /// fetches the configuration and applies it if it is newer than the current one, returning whether it was.
+    /// a server without a configuration for the camera leaves its settings as they are
+    pub async fn reload(&self) -> Result<bool, Box<dyn Error>> {
+        if self.secret.is_none() {
+            return Err(
+                format!("{CONTROL_SECRET_VAR} isnt set, so configuration cant be checked").into(),
+            );
+        }
+        let response = self
+            .client
+            .get(self.addr.join("config/")?.join(&self.camera_id)?)
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        let text = response.error_for_status()?.text().await?;
+        self.apply(&text)
+    }
+
+    fn apply(&self, text: &str) -> Result<bool, Box<dyn Error>> {
+        let current_version = self.version.load(Ordering::Relaxed);
+        let secret = self.secret.as_deref().ok_or("no control secret")?;
+        let Some((version, config)) = open_config(text, secret, current_version)? else {
+            return Ok(false);
+        };
+        // checked before anything is applied, so a bad schedule doesnt leave half a configuration in place
+        let arming = config.arming.map(TryInto::try_into).transpose()?;
+        if let Some(arming) = arming {
+            self.status.arming.set_schedule(arming);
+        }
+        if let Some(detection) = config.detection {
+            self.detection
+                .send_modify(|config| detection.apply_to(config));
+        }
+        self.version.store(version, Ordering::Relaxed);
+        if let Some(path) = &self.version_file {
+            if let Err(e) = save_version(path, version) {
+                warn!("saving configuration version to {}: {e}", path.display());
+            }
+        }
+        info!(version, "applied camera configuration");
+        Ok(true)
+    }
+}
+
+/// reads the version of the last document applied from path, where apply saves it. 0 if there isnt one yet
+pub fn load_version(path: &Path) -> u64 {
+    match std::fs::read_to_string(path) {
+        Ok(text) => text.trim().parse().unwrap_or_else(|_| {
+            warn!("{} doesnt hold a configuration version", path.display());
+            0
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        Err(e) => {
+            warn!("reading configuration version from {}: {e}", path.display());
+            0
+        }
+    }
+}
+
+/// written to a temporary file first so a crash never leaves half a version behind
+fn save_version(path: &Path, version: u64) -> Result<(), std::io::Error> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    std::fs::write(&temp, version.to_string())?;
+    std::fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arming::ArmMode;
+    use crate::control::sign;
+    use image::{GrayImage, Luma};
+
+    fn document(version: u64, body: &str, secret: &str) -> String {
+        let mac = sign(secret, &format!("{version}.{body}"));
+        serde_json::json!({ "version": version, "body": body, "mac": mac }).to_string()
+    }
+
+    /// what the camera was started with, eg. from SECURITY_CAM_*
+    fn local_detection() -> DetectionConfig {
+        DetectionConfig {
+            threshold: 40,
+            zones: vec!["door:0,0,4,4".parse().unwrap()],
+            ..Default::default()
+        }
+    }
+
+    fn remote_config() -> RemoteConfig {
+        RemoteConfig {
+            client: reqwest::Client::new(),
+            addr: Url::parse("http://localhost").unwrap(),
+            camera_id: "garage".to_string(),
+            secret: Some("secret".to_string()),
+            poll_interval: Duration::from_secs(300),
+            detection: watch::Sender::new(local_detection()),
+            status: Arc::new(CameraStatus::default()),
+            version: Arc::new(AtomicU64::new(0)),
+            version_file: None,
+        }
+    }
+
+    #[test]
+    fn test_applies_newer_signed_config() {
+        let remote = remote_config();
+        let detection = remote.detection.subscribe();
+        let body = r#"{"detection": {"min_score": 30, "masks": [{"x": 0, "y": 0, "width": 4, "height": 2}]}}"#;
+
+        assert!(remote.apply(&document(2, body, "wrong")).is_err());
+        let unsigned = serde_json::json!({ "version": 2, "body": body }).to_string();
+        assert!(remote.apply(&unsigned).is_err());
+        assert!(remote.apply(&document(2, body, "secret")).unwrap());
+        let applied = detection.borrow().clone();
+        assert_eq!(applied.min_score, 30);
+        // fields the document leaves out keep the camera's own settings
+        assert_eq!(applied.threshold, 40);
+        assert_eq!(applied.zones, local_detection().zones);
+
+        // the same version again, or an older one, is left alone
+        let older = r#"{"detection": {"min_score": 1}}"#;
+        assert!(!remote.apply(&document(2, older, "secret")).unwrap());
+        assert!(!remote.apply(&document(1, older, "secret")).unwrap());
+        assert_eq!(detection.borrow().min_score, 30);
+
+        let mut diff = GrayImage::from_pixel(8, 8, Luma([255]));
+        applied.apply_masks(&mut diff);
+        assert_eq!(diff.pixels().filter(|pixel| pixel[0] == 0).count(), 8);
+        assert_eq!(
+            applied.masks,
+            vec!["0,0,4,2".parse::<BoundingBox>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_config_without_detection_keeps_settings() {
+        let remote = remote_config();
+        let detection = remote.detection.subscribe();
+        assert!(remote.apply(&document(1, "{}", "secret")).unwrap());
+        assert_eq!(*detection.borrow(), local_detection());
+        assert_eq!(remote.version.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_version_survives_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "security-cam-config-version-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(load_version(&path), 0);
+        let mut remote = remote_config();
+        remote.version_file = Some(path.clone());
+        assert!(remote.apply(&document(2, "{}", "secret")).unwrap());
+
+        // once restarted, the camera still doesnt take an older document
+        let restarted = remote_config();
+        restarted
+            .version
+            .store(load_version(&path), Ordering::Relaxed);
+        let older = r#"{"detection": {"min_score": 1}}"#;
+        assert!(!restarted.apply(&document(1, older, "secret")).unwrap());
+        assert_eq!(*restarted.detection.borrow(), local_detection());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_masks_past_the_edge() {
+        let remote = remote_config();
+        let body = r#"{"detection": {"masks": [{"x": 4294967295, "y": 0, "width": 10, "height": 10}], "zones": [{"name": "far", "area": {"x": 1, "y": 4294967290, "width": 4294967295, "height": 10}}]}}"#;
+        assert!(remote.apply(&document(1, body, "secret")).unwrap());
+        let applied = remote.detection.borrow().clone();
+
+        let mut diff = GrayImage::from_pixel(8, 8, Luma([255]));
+        applied.apply_masks(&mut diff);
+        assert!(diff.pixels().all(|pixel| pixel[0] == 255));
+        let near = "0,0,2,2".parse::<BoundingBox>().unwrap();
+        assert!(applied.zones_hit(&[near]).is_empty());
+    }
+
+    #[test]
+    fn test_applies_arm_schedule() {
+        let remote = remote_config();
+        let bad = r#"{"detection": {"min_score": 30}, "arming": {"timezone": "UTC", "schedule": "mon 00:00-00:00 off"}}"#;
+        assert!(remote.apply(&document(1, bad, "secret")).is_err());
+        assert_eq!(*remote.detection.borrow(), local_detection());
+
+        let body = r#"{"arming": {"timezone": "UTC", "default": "ignore"}}"#;
+        assert!(remote.apply(&document(1, body, "secret")).unwrap());
+        assert_eq!(remote.status.arming.mode(), ArmMode::Ignore);
+    }
+}
@@ -0,0 +1,127 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use std::error::Error;
+use std::str::FromStr;
+
+const DAYS: [(&str, Weekday); 7] = [
+    ("mon", Weekday::Mon),
+    ("tue", Weekday::Tue),
+    ("wed", Weekday::Wed),
+    ("thu", Weekday::Thu),
+    ("fri", Weekday::Fri),
+    ("sat", Weekday::Sat),
+    ("sun", Weekday::Sun),
+];
+
+/// a time of day range on some days of the week, written like `mon-fri 09:00-17:00`, `sat 10:00-12:00`
+/// or `daily 22:00-06:00`. ranges that end before they start run past midnight into the next day
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TimeWindow {
+    /// indexed by Weekday::num_days_from_monday
+    days: [bool; 7],
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    /// true if now, in whatever timezone the schedule is written in, falls inside the window
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let today = now.weekday();
+        let time = now.time();
+        if self.start <= self.end {
+            self.on(today) && self.start <= time && time < self.end
+        } else {
+            (self.on(today) && time >= self.start) || (self.on(today.pred()) && time < self.end)
+        }
+    }
+
+    fn on(&self, day: Weekday) -> bool {
+        self.days[day.num_days_from_monday() as usize]
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days, times) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("expected `<days> <HH:MM>-<HH:MM>`, got {s}"))?;
+        let (start, end) = times
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| format!("expected a time range like 09:00-17:00, got {times}"))?;
+        Ok(TimeWindow {
+            days: parse_days(days)?,
+            start: NaiveTime::parse_from_str(start.trim(), "%H:%M")?,
+            end: NaiveTime::parse_from_str(end.trim(), "%H:%M")?,
+        })
+    }
+}
+
+/// parses `daily`, a single day like `sat`, or a range like `mon-fri` or `fri-mon`
+fn parse_days(days: &str) -> Result<[bool; 7], Box<dyn Error>> {
+    let days = days.to_ascii_lowercase();
+    if days == "daily" {
+        return Ok([true; 7]);
+    }
+    let day = |name: &str| {
+        DAYS.iter()
+            .position(|(day, _)| *day == name)
+            .ok_or_else(|| format!("unknown day {name}, expected mon, tue, wed, thu, fri, sat or sun"))
+    };
+    let (first, last) = match days.split_once('-') {
+        Some((first, last)) => (day(first)?, day(last)?),
+        None => (day(&days)?, day(&days)?),
+    };
+    let mut selected = [false; 7];
+    let mut i = first;
+    loop {
+        selected[i] = true;
+        if i == last {
+            break;
+        }
+        i = (i + 1) % 7;
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        // 2024-01-01 was a monday
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn test_business_hours() {
+        let window: TimeWindow = "mon-fri 09:00-17:00".parse().unwrap();
+        assert!(window.contains(at(1, "09:00")));
+        assert!(window.contains(at(5, "16:59")));
+        assert!(!window.contains(at(5, "17:00")));
+        assert!(!window.contains(at(6, "12:00")));
+    }
+
+    #[test]
+    fn test_past_midnight() {
+        let window: TimeWindow = "fri-sat 22:00-06:00".parse().unwrap();
+        assert!(window.contains(at(5, "23:00")));
+        assert!(window.contains(at(6, "05:00")));
+        // sunday morning is still part of saturday night
+        assert!(window.contains(at(7, "05:00")));
+        assert!(!window.contains(at(5, "05:00")));
+        assert!(!window.contains(at(7, "22:00")));
+    }
+
+    #[test]
+    fn test_invalid() {
+        "weekdays 09:00-17:00".parse::<TimeWindow>().unwrap_err();
+        "mon-fri 9-5".parse::<TimeWindow>().unwrap_err();
+        "mon-fri".parse::<TimeWindow>().unwrap_err();
+    }
+}
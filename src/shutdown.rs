@@ -0,0 +1,83 @@
+use actix_web::rt::signal;
+use security_cam_common::shuttle_runtime::tokio;
+use std::error::Error;
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// environment variable holding how long to wait for recordings to finish uploading after being asked to stop
+pub const SHUTDOWN_SECONDS_VAR: &str = "SECURITY_CAM_SHUTDOWN_SECONDS";
+
+/// exit code for capture stopping on its own, eg. the camera went away. bad configuration exits with 1
+pub const EXIT_CAPTURE_STOPPED: u8 = 2;
+
+/// exit code for stopping with recordings still uploading, so they are incomplete on the server and the rest is in
+/// video_frames
+pub const EXIT_UPLOADS_UNFINISHED: u8 = 3;
+
+#[derive(Clone, Debug)]
+pub struct ShutdownConfig {
+    /// from the stop signal to giving up on anything still uploading
+    pub deadline: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            deadline: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let mut config = ShutdownConfig::default();
+        if let Ok(seconds) = std::env::var(SHUTDOWN_SECONDS_VAR) {
+            let seconds: u64 = seconds
+                .trim()
+                .parse()
+                .ok()
+                .filter(|seconds| *seconds > 0)
+                .ok_or_else(|| format!("{SHUTDOWN_SECONDS_VAR} must be a positive integer"))?;
+            config.deadline = Duration::from_secs(seconds);
+        }
+        Ok(config)
+    }
+}
+
+/// how a run of the client ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// stopped by a signal with every recording finished
+    Stopped,
+    CaptureStopped,
+    UploadsUnfinished,
+}
+
+impl From<Outcome> for ExitCode {
+    fn from(outcome: Outcome) -> Self {
+        match outcome {
+            Outcome::Stopped => ExitCode::SUCCESS,
+            Outcome::CaptureStopped => ExitCode::from(EXIT_CAPTURE_STOPPED),
+            Outcome::UploadsUnfinished => ExitCode::from(EXIT_UPLOADS_UNFINISHED),
+        }
+    }
+}
+
+/// waits for SIGINT or SIGTERM, returning the name of the one that came
+#[cfg(unix)]
+pub async fn stop_signal() -> Result<&'static str, std::io::Error> {
+    use signal::unix::{signal, SignalKind};
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    })
+}
+
+/// waits for ctrl-c, the only stop signal there is off unix
+#[cfg(not(unix))]
+pub async fn stop_signal() -> Result<&'static str, std::io::Error> {
+    signal::ctrl_c().await?;
+    Ok("ctrl-c")
+}
@@ -0,0 +1,177 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::ImageFormat;
+use reqwest::Url;
+use security_cam_common::shuttle_runtime::tokio;
+use std::error::Error;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::framing::encrypt_single;
+use crate::keys::KeyCache;
+use crate::motiondetection::Frame;
+use crate::networking::KEY_ID_HEADER;
+
+/// how many frames at the start of a recording are looked at for the thumbnail before it is sent
+pub const THUMBNAIL_FRAMES: usize = 10;
+
+/// thumbnails fit in this size, keeping the camera's aspect ratio
+const THUMBNAIL_WIDTH: u32 = 320;
+const THUMBNAIL_HEIGHT: u32 = 240;
+const THUMBNAIL_QUALITY: u8 = 70;
+
+/// keeps the frame with the highest motion score out of the first THUMBNAIL_FRAMES of a recording
+#[derive(Default)]
+pub struct ThumbnailPicker {
+    /// (motion score, timestamp, jpeg)
+    best: Option<(u32, u64, Vec<u8>)>,
+    seen: usize,
+}
+
+impl ThumbnailPicker {
+    /// considers frame for the thumbnail, returns true once enough frames have been seen to send it
+    pub fn observe(&mut self, frame: &Frame) -> bool {
+        if self.seen < THUMBNAIL_FRAMES
+            && self
+                .best
+                .as_ref()
+                .map_or(true, |(score, _, _)| frame.motion_score > *score)
+        {
+            self.best = Some((
+                frame.motion_score,
+                frame.timestamp_ms,
+                frame.frame_bytes.clone(),
+            ));
+        }
+        self.seen += 1;
+        self.seen >= THUMBNAIL_FRAMES
+    }
+}
+
+/// sends a recording's thumbnail to `upload_thumbnail/<video>` as soon as it has been picked,
+/// so a notification can show it before the recording is done uploading
+pub struct ThumbnailUpload {
+    client: reqwest::Client,
+    url: Url,
+    key_id: String,
+    keys: Arc<KeyCache>,
+    video_num: usize,
+    picker: ThumbnailPicker,
+}
+
+impl ThumbnailUpload {
+    pub fn new(
+        client: reqwest::Client,
+        url: Url,
+        key_id: String,
+        keys: Arc<KeyCache>,
+        video_num: usize,
+    ) -> Self {
+        ThumbnailUpload {
+            client,
+            url,
+            key_id,
+            keys,
+            video_num,
+            picker: ThumbnailPicker::default(),
+        }
+    }
+
+    /// considers frame for the thumbnail, sending it once enough frames have been seen.
+    /// returns true once the thumbnail has been sent
+    pub fn observe(&mut self, frame: &Frame) -> bool {
+        let ready = self.picker.observe(frame);
+        if ready {
+            self.send();
+        }
+        ready
+    }
+
+    /// shrinks, encrypts and uploads the best frame so far in the background
+    pub fn send(&mut self) {
+        let Some((_, timestamp_ms, jpeg)) = self.picker.best.take() else {
+            return;
+        };
+        let client = self.client.clone();
+        let url = self.url.clone();
+        let key_id = self.key_id.clone();
+        let keys = self.keys.clone();
+        let video_num = self.video_num;
+        actix_web::rt::spawn(async move {
+            let result = async {
+                let thumbnail =
+                    tokio::task::spawn_blocking(move || make_thumbnail(&jpeg)).await??;
+                let body = encrypt_single(keys.take().await?, timestamp_ms, &thumbnail)?;
+                client
+                    .post(url)
+                    .header(KEY_ID_HEADER, key_id)
+                    .body(body)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok::<(), Box<dyn Error>>(())
+            }
+            .await;
+            if let Err(e) = result {
+                warn!(video = video_num, "sending thumbnail: {e}");
+            }
+        });
+    }
+}
+
+/// shrinks a jpeg frame down to thumbnail size
+pub fn make_thumbnail(jpeg: &[u8]) -> Result<Vec<u8>, image::ImageError> {
+    let image = image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg)?
+        .thumbnail(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT);
+    let mut thumbnail = Vec::new();
+    JpegEncoder::new_with_quality(&mut thumbnail, THUMBNAIL_QUALITY)
+        .encode_image(&image.to_rgb8())?;
+    Ok(thumbnail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    fn frame(motion_score: u32) -> Frame {
+        Frame {
+            video_num: 0,
+            frame_bytes: vec![motion_score as u8],
+            fps: 25,
+            end: false,
+            timestamp_ms: motion_score as u64,
+            motion_score,
+            bounding_boxes: Vec::new(),
+            zones: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_picks_highest_score_early_on() {
+        let mut picker = ThumbnailPicker::default();
+        let scores = [3, 40, 12];
+        for score in scores {
+            assert!(!picker.observe(&frame(score)));
+        }
+        for _ in scores.len()..THUMBNAIL_FRAMES - 1 {
+            assert!(!picker.observe(&frame(1)));
+        }
+        assert!(picker.observe(&frame(1)));
+        // past the first frames it is too late to change the thumbnail
+        picker.observe(&frame(99));
+        assert_eq!(picker.best.unwrap().2, vec![40]);
+    }
+
+    #[test]
+    fn test_thumbnail_keeps_aspect_ratio() {
+        let image = RgbImage::from_fn(1280, 720, |x, y| Rgb([x as u8, y as u8, 64]));
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 90)
+            .encode_image(&image)
+            .unwrap();
+
+        let thumbnail = make_thumbnail(&jpeg).unwrap();
+        let decoded = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!(decoded.dimensions(), (320, 180));
+    }
+}
@@ -0,0 +1,394 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::credentials::check_permissions;
+
+/// environment variable holding a pem file of extra certificate authorities to trust
+pub const CA_FILE_VAR: &str = "SECURITY_CAM_TLS_CA_FILE";
+
+/// environment variable holding the pem certificate chain presented to the server for mutual tls
+pub const CLIENT_CERT_VAR: &str = "SECURITY_CAM_TLS_CLIENT_CERT";
+
+/// environment variable holding the pem private key for the client certificate
+pub const CLIENT_KEY_VAR: &str = "SECURITY_CAM_TLS_CLIENT_KEY";
+
+/// environment variable holding comma separated `sha256/<base64>` pins of the server's public key
+pub const PINS_VAR: &str = "SECURITY_CAM_TLS_PINS";
+
+/// tls options for talking to a self hosted server
+#[derive(Default, Clone, Debug)]
+pub struct TlsConfig {
+    /// certificate authorities trusted on top of the built in ones
+    pub ca_file: Option<PathBuf>,
+
+    /// certificate chain and key presented to the server for mutual tls
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+
+    /// sha256 hashes of the SubjectPublicKeyInfo of a certificate in the server's chain.
+    /// if any are set, the server has to both pass normal validation and match one of them
+    pub pins: Vec<[u8; 32]>,
+}
+
+impl TlsConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let pins = match std::env::var(PINS_VAR) {
+            Ok(pins) => pins
+                .split(',')
+                .filter(|pin| !pin.trim().is_empty())
+                .map(parse_pin)
+                .collect::<Result<_, _>>()?,
+            Err(_) => Vec::new(),
+        };
+        Ok(TlsConfig {
+            ca_file: std::env::var_os(CA_FILE_VAR).map(PathBuf::from),
+            client_cert: std::env::var_os(CLIENT_CERT_VAR).map(PathBuf::from),
+            client_key: std::env::var_os(CLIENT_KEY_VAR).map(PathBuf::from),
+            pins,
+        })
+    }
+
+    /// true if nothing is configured and the http client's default tls setup can be used
+    pub fn is_default(&self) -> bool {
+        self.ca_file.is_none()
+            && self.client_cert.is_none()
+            && self.client_key.is_none()
+            && self.pins.is_empty()
+    }
+
+    /// builds a rustls config to hand to reqwest::ClientBuilder::use_preconfigured_tls
+    pub fn build(&self) -> Result<rustls::ClientConfig, Box<dyn Error>> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        if let Some(ca_file) = &self.ca_file {
+            for cert in read_certs(ca_file)? {
+                roots.add(cert)?;
+            }
+        }
+
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = if self.pins.is_empty() {
+            builder.with_root_certificates(roots)
+        } else {
+            let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()?;
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    inner,
+                    pins: self.pins.clone(),
+                }))
+        };
+
+        let mut config = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                builder.with_client_auth_cert(read_certs(cert)?, read_private_key(key)?)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(format!(
+                    "mutual tls needs both {CLIENT_CERT_VAR} and {CLIENT_KEY_VAR} to be set"
+                )
+                .into())
+            }
+        };
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+/// parses a pin in the `sha256/<base64>` form used by curl's --pinnedpubkey, the prefix is optional
+pub fn parse_pin(pin: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let pin = pin.trim();
+    let encoded = pin.strip_prefix("sha256/").unwrap_or(pin);
+    STANDARD
+        .decode(encoded)?
+        .try_into()
+        .map_err(|_| format!("pin {pin} is not a base64 sha256 hash").into())
+}
+
+/// the sha256 hash of a certificate's SubjectPublicKeyInfo, which is what pins are compared against
+pub fn spki_sha256(cert: &CertificateDer<'_>) -> Result<[u8; 32], rustls::Error> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref())
+        .map_err(|e| rustls::Error::General(format!("couldnt parse certificate: {e}")))?;
+    Ok(Sha256::digest(cert.tbs_certificate.subject_pki.raw).into())
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", path.display()).into());
+    }
+    Ok(certs)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, Box<dyn Error>> {
+    check_permissions(path)?;
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| format!("no private key found in {}", path.display()).into())
+}
+
+/// does the normal webpki validation, then checks that the chain contains a pinned public key
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        for cert in std::iter::once(end_entity).chain(intermediates) {
+            if self.pins.contains(&spki_sha256(cert)?) {
+                return Ok(ServerCertVerified::assertion());
+            }
+        }
+        Err(rustls::Error::General(format!(
+            "server certificate doesnt match any pinned public key, its pin is sha256/{}",
+            STANDARD.encode(spki_sha256(end_entity)?)
+        )))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::server::WebPkiClientVerifier;
+    use security_cam_common::shuttle_runtime::tokio;
+    use std::net::SocketAddr;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// a private ca with a server certificate for localhost and a client certificate signed by it, written to a
+    /// temporary directory that is removed again when it is dropped
+    struct TestPki {
+        dir: PathBuf,
+        ca: Certificate,
+        server_cert: Certificate,
+        server_key: KeyPair,
+    }
+
+    impl TestPki {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("security-cam-tls-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let ca_key = KeyPair::generate().unwrap();
+            let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = ca_params.self_signed(&ca_key).unwrap();
+
+            let server_key = KeyPair::generate().unwrap();
+            let mut server_params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            server_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+            let server_cert = server_params.signed_by(&server_key, &ca, &ca_key).unwrap();
+
+            let client_key = KeyPair::generate().unwrap();
+            let mut client_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let client_cert = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+            std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+            std::fs::write(dir.join("client.pem"), client_cert.pem()).unwrap();
+            std::fs::write(dir.join("client.key"), client_key.serialize_pem()).unwrap();
+            std::fs::set_permissions(dir.join("client.key"), std::fs::Permissions::from_mode(0o600))
+                .unwrap();
+            TestPki {
+                dir,
+                ca,
+                server_cert,
+                server_key,
+            }
+        }
+
+        fn server_config(&self, require_client_cert: bool) -> rustls::ServerConfig {
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+                .with_safe_default_protocol_versions()
+                .unwrap();
+            let builder = if require_client_cert {
+                let mut roots = RootCertStore::empty();
+                roots.add(self.ca.der().clone()).unwrap();
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()
+                        .unwrap();
+                builder.with_client_cert_verifier(verifier)
+            } else {
+                builder.with_no_client_auth()
+            };
+            builder
+                .with_single_cert(
+                    vec![self.server_cert.der().clone()],
+                    PrivateKeyDer::Pkcs8(self.server_key.serialize_der().into()),
+                )
+                .unwrap()
+        }
+
+        fn config(&self) -> TlsConfig {
+            TlsConfig {
+                ca_file: Some(self.dir.join("ca.pem")),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Drop for TestPki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// tls stand in for the server that answers every request with a 200
+    async fn spawn_server(config: rustls::ServerConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok")
+                        .await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        addr
+    }
+
+    /// sends a request to the stand in with the given tls config, returning the whole error chain on failure
+    async fn get(config: &TlsConfig, addr: SocketAddr) -> Result<String, String> {
+        let client = reqwest::Client::builder()
+            .use_preconfigured_tls(config.build().map_err(|e| e.to_string())?)
+            .resolve("localhost", addr)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let result = async {
+            client
+                .get(format!("https://localhost:{}/", addr.port()))
+                .send()
+                .await?
+                .text()
+                .await
+        }
+        .await;
+        result.map_err(|e| {
+            let mut chain = e.to_string();
+            let mut source = std::error::Error::source(&e);
+            while let Some(e) = source {
+                chain.push_str(&format!(": {e}"));
+                source = e.source();
+            }
+            chain
+        })
+    }
+
+    #[tokio::test]
+    async fn test_private_ca() {
+        let pki = TestPki::new("ca");
+        let addr = spawn_server(pki.server_config(false)).await;
+        get(&TlsConfig::default(), addr).await.unwrap_err();
+        assert_eq!(get(&pki.config(), addr).await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn test_pinning() {
+        let pki = TestPki::new("pinning");
+        let addr = spawn_server(pki.server_config(false)).await;
+
+        let mut config = pki.config();
+        config.pins = vec![[0; 32]];
+        let err = get(&config, addr).await.unwrap_err();
+        assert!(err.contains("pinned public key"), "{err}");
+
+        config.pins = vec![spki_sha256(pki.server_cert.der()).unwrap()];
+        assert_eq!(get(&config, addr).await.unwrap(), "ok");
+
+        // the stand in only sends its own certificate, so a pinned ca key is never seen
+        config.pins = vec![spki_sha256(pki.ca.der()).unwrap()];
+        get(&config, addr).await.unwrap_err();
+
+        let pin = format!("sha256/{}", STANDARD.encode(spki_sha256(pki.server_cert.der()).unwrap()));
+        assert_eq!(parse_pin(&pin).unwrap(), spki_sha256(pki.server_cert.der()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let pki = TestPki::new("mtls");
+        let addr = spawn_server(pki.server_config(true)).await;
+        get(&pki.config(), addr).await.unwrap_err();
+
+        let mut config = pki.config();
+        config.client_cert = Some(pki.dir.join("client.pem"));
+        config.client_key = Some(pki.dir.join("client.key"));
+        assert_eq!(get(&config, addr).await.unwrap(), "ok");
+
+        config.client_key = None;
+        config.build().unwrap_err();
+    }
+}
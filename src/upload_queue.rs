@@ -0,0 +1,659 @@
+use actix_web::web::Bytes;
+use security_cam_common::shuttle_runtime::tokio::{self, sync::Notify};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::adaptive::{QualityChange, ADAPTIVE_QUALITY_VAR};
+use crate::metrics::METRICS;
+
+/// environment variable selecting the backpressure policy: block, drop-oldest, reduce-framerate or spill
+pub const BACKPRESSURE_VAR: &str = "SECURITY_CAM_BACKPRESSURE";
+
+/// environment variable holding how many frames can wait in memory for the upload
+pub const QUEUE_FRAMES_VAR: &str = "SECURITY_CAM_UPLOAD_QUEUE_FRAMES";
+
+/// environment variable holding how many recordings can upload at the same time
+pub const MAX_CONCURRENT_VAR: &str = "SECURITY_CAM_MAX_CONCURRENT_UPLOADS";
+
+/// environment variable selecting how recordings are uploaded: framed, chunked or legacy
+pub const UPLOAD_FORMAT_VAR: &str = "SECURITY_CAM_UPLOAD_FORMAT";
+
+/// environment variable holding how many frames go in each segment of a chunked upload
+pub const SEGMENT_FRAMES_VAR: &str = "SECURITY_CAM_SEGMENT_FRAMES";
+
+/// what to do with a new frame when the upload cant keep up and the queue is full
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// wait for the upload to catch up, which eventually stalls the capture thread
+    #[default]
+    Block,
+
+    /// throw away the oldest queued frame to make room
+    DropOldest,
+
+    /// keep every other frame once the queue is half full, and drop new frames once it is full
+    ReduceFrameRate,
+
+    /// write frames that dont fit to disk and upload them from there in order
+    SpillToDisk,
+}
+
+impl FromStr for BackpressurePolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "block" => Ok(BackpressurePolicy::Block),
+            "drop-oldest" => Ok(BackpressurePolicy::DropOldest),
+            "reduce-framerate" => Ok(BackpressurePolicy::ReduceFrameRate),
+            "spill" => Ok(BackpressurePolicy::SpillToDisk),
+            other => Err(format!(
+                "unknown backpressure policy {other}, expected block, drop-oldest, reduce-framerate or spill"
+            )
+            .into()),
+        }
+    }
+}
+
+/// how a recording's frames are laid out in the upload
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadFormat {
+    /// each frame is encrypted on its own with its length and timestamp in front, see framing
+    #[default]
+    Framed,
+
+    /// framed, but sent as acknowledged segments that survive the connection dropping, see chunked
+    Chunked,
+
+    /// one encrypted stream split into chunks the size of the first frame, for servers that dont take framed
+    /// uploads. only works if every frame is the same size
+    Legacy,
+}
+
+impl FromStr for UploadFormat {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "framed" => Ok(UploadFormat::Framed),
+            "chunked" => Ok(UploadFormat::Chunked),
+            "legacy" => Ok(UploadFormat::Legacy),
+            other => Err(format!(
+                "unknown upload format {other}, expected framed, chunked or legacy"
+            )
+            .into()),
+        }
+    }
+}
+
+/// how frames are queued between the main loop and the transfer task
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    pub policy: BackpressurePolicy,
+
+    /// frames held in memory before the policy kicks in
+    pub queue_frames: usize,
+
+    /// re-encode frames at a lower quality when the upload falls behind, never done for Legacy uploads
+    pub adaptive_quality: bool,
+
+    /// recordings past this many wait until an earlier upload finishes, with their frames spilled to disk past
+    /// queue_frames whatever the policy, see UploadSender::hold
+    pub max_concurrent: usize,
+
+    pub format: UploadFormat,
+
+    /// frames in each segment when the format is Chunked
+    pub segment_frames: usize,
+
+    /// where frames are spilled to, and left if the client stops before they are uploaded
+    pub spill_dir: PathBuf,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            policy: BackpressurePolicy::Block,
+            queue_frames: 5,
+            adaptive_quality: false,
+            max_concurrent: 2,
+            format: UploadFormat::Framed,
+            segment_frames: 25,
+            spill_dir: "video_frames".into(),
+        }
+    }
+}
+
+impl UploadConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let mut config = UploadConfig::default();
+        if let Ok(policy) = std::env::var(BACKPRESSURE_VAR) {
+            config.policy = policy.parse()?;
+        }
+        if let Ok(queue_frames) = std::env::var(QUEUE_FRAMES_VAR) {
+            config.queue_frames = queue_frames
+                .trim()
+                .parse()
+                .map_err(|_| format!("{QUEUE_FRAMES_VAR} must be a positive integer"))?;
+            if config.queue_frames == 0 {
+                return Err(format!("{QUEUE_FRAMES_VAR} must be a positive integer").into());
+            }
+        }
+        if let Ok(max_concurrent) = std::env::var(MAX_CONCURRENT_VAR) {
+            config.max_concurrent = max_concurrent
+                .trim()
+                .parse()
+                .ok()
+                .filter(|max_concurrent| *max_concurrent > 0)
+                .ok_or_else(|| format!("{MAX_CONCURRENT_VAR} must be a positive integer"))?;
+        }
+        if let Ok(format) = std::env::var(UPLOAD_FORMAT_VAR) {
+            config.format = format.parse()?;
+        }
+        if let Ok(segment_frames) = std::env::var(SEGMENT_FRAMES_VAR) {
+            config.segment_frames = segment_frames
+                .trim()
+                .parse()
+                .ok()
+                .filter(|segment_frames| *segment_frames > 0)
+                .ok_or_else(|| format!("{SEGMENT_FRAMES_VAR} must be a positive integer"))?;
+        }
+        if let Ok(adaptive_quality) = std::env::var(ADAPTIVE_QUALITY_VAR) {
+            config.adaptive_quality = matches!(adaptive_quality.trim(), "1" | "true" | "on");
+        }
+        // re-encoded frames change size, which legacy uploads cant cope with
+        if config.adaptive_quality && config.format == UploadFormat::Legacy {
+            return Err(format!(
+                "{ADAPTIVE_QUALITY_VAR} needs {UPLOAD_FORMAT_VAR} set to framed or chunked"
+            )
+            .into());
+        }
+        Ok(config)
+    }
+}
+
+/// counters for a single recording
+#[derive(Default, Debug)]
+pub struct UploadStats {
+    /// frames handed to the queue
+    pub frames: AtomicUsize,
+
+    /// frames thrown away by the backpressure policy or left out by adaptive quality
+    pub dropped: AtomicUsize,
+
+    /// frames that were written to disk because the queue was full
+    pub spilled: AtomicUsize,
+
+    /// quality changes made by adaptive quality during the recording
+    pub quality_changes: Mutex<Vec<QualityChange>>,
+}
+
+impl std::fmt::Display for UploadStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} frames, {} dropped, {} spilled to disk",
+            self.frames.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.spilled.load(Ordering::Relaxed)
+        )?;
+        for change in self.quality_changes.lock().unwrap().iter() {
+            match change.quality {
+                Some(quality) => write!(
+                    f,
+                    ", from frame {} jpeg quality {} at {}% size keeping every {} frames",
+                    change.frame, quality.jpeg_quality, quality.scale_percent, quality.keep_every
+                )?,
+                None => write!(f, ", from frame {} original quality", change.frame)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// queued frames keep the time they were captured at, in ms since the unix epoch
+enum Entry {
+    Memory(u64, Bytes),
+    Disk(u64, PathBuf),
+}
+
+struct State {
+    entries: VecDeque<Entry>,
+    in_memory: usize,
+    closed: bool,
+    receiver_gone: bool,
+
+    /// flips for every frame while reducing the frame rate
+    skip_next: bool,
+    spill_count: usize,
+
+    /// set while a QueueHold is alive
+    held: bool,
+
+    /// set once what was left has been written to disk to be kept, see QueueSpool
+    spooled: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    items: Notify,
+    space: Notify,
+    config: UploadConfig,
+    stats: Arc<UploadStats>,
+
+    /// spilled frames are written to {spill_prefix}.{n}.jpg
+    spill_prefix: PathBuf,
+}
+
+/// creates a queue of frames for one recording, applying config.policy when the receiving end falls behind
+pub fn upload_queue(config: UploadConfig, spill_prefix: PathBuf) -> (UploadSender, UploadReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            entries: VecDeque::new(),
+            in_memory: 0,
+            closed: false,
+            receiver_gone: false,
+            skip_next: false,
+            spill_count: 0,
+            held: false,
+            spooled: false,
+        }),
+        items: Notify::new(),
+        space: Notify::new(),
+        config,
+        stats: Arc::new(UploadStats::default()),
+        spill_prefix,
+    });
+    (
+        UploadSender {
+            shared: shared.clone(),
+        },
+        UploadReceiver { shared },
+    )
+}
+
+/// the main loop's end of the queue, closes the queue when dropped
+pub struct UploadSender {
+    shared: Arc<Shared>,
+}
+
+impl UploadSender {
+    pub fn stats(&self) -> Arc<UploadStats> {
+        self.shared.stats.clone()
+    }
+
+    /// holds the queue until the returned QueueHold is dropped, eg. while the upload waits for its turn. in the
+    /// meantime frames past queue_frames are spilled to disk under the Block policy instead of waiting, so a
+    /// recording that hasnt started uploading yet never holds up capture
+    pub fn hold(&self) -> QueueHold {
+        self.shared.state.lock().unwrap().held = true;
+        QueueHold {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// for keeping what is left in the queue if the upload cant finish, usable after the sender is dropped
+    pub fn spool(&self) -> QueueSpool {
+        QueueSpool {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// queues a frame captured at timestamp_ms, only waits if the policy is Block and the queue is full
+    pub async fn push(&mut self, frame: Vec<u8>, timestamp_ms: u64) -> Result<(), std::io::Error> {
+        let shared = &self.shared;
+        shared.stats.frames.fetch_add(1, Ordering::Relaxed);
+        loop {
+            let space = shared.space.notified();
+            let spill_path = {
+                let mut state = shared.state.lock().unwrap();
+                if state.receiver_gone || state.spooled {
+                    return Err(std::io::Error::new(
+                        ErrorKind::BrokenPipe,
+                        "the upload has stopped",
+                    ));
+                }
+                let capacity = shared.config.queue_frames;
+                if shared.config.policy == BackpressurePolicy::ReduceFrameRate
+                    && state.in_memory * 2 >= capacity
+                {
+                    state.skip_next = !state.skip_next;
+                    if state.skip_next || state.in_memory >= capacity {
+                        shared.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                        METRICS.frames_dropped.inc();
+                        return Ok(());
+                    }
+                }
+                if state.in_memory < capacity {
+                    state
+                        .entries
+                        .push_back(Entry::Memory(timestamp_ms, Bytes::from(frame)));
+                    state.in_memory += 1;
+                    shared.items.notify_one();
+                    return Ok(());
+                }
+                let policy = match shared.config.policy {
+                    BackpressurePolicy::Block if state.held => BackpressurePolicy::SpillToDisk,
+                    policy => policy,
+                };
+                match policy {
+                    BackpressurePolicy::Block => None,
+                    BackpressurePolicy::DropOldest => {
+                        if let Some(i) = state
+                            .entries
+                            .iter()
+                            .position(|entry| matches!(entry, Entry::Memory(..)))
+                        {
+                            state.entries.remove(i);
+                            state.in_memory -= 1;
+                            shared.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                            METRICS.frames_dropped.inc();
+                        }
+                        state
+                            .entries
+                            .push_back(Entry::Memory(timestamp_ms, Bytes::from(frame)));
+                        state.in_memory += 1;
+                        shared.items.notify_one();
+                        return Ok(());
+                    }
+                    BackpressurePolicy::ReduceFrameRate => unreachable!("dropped above"),
+                    BackpressurePolicy::SpillToDisk => {
+                        state.spill_count += 1;
+                        let mut path = shared.spill_prefix.clone().into_os_string();
+                        path.push(format!(".{}.jpg", state.spill_count));
+                        Some(PathBuf::from(path))
+                    }
+                }
+            };
+            match spill_path {
+                // only one sender writes to the queue, so nothing can be pushed between
+                // writing the file and queueing it
+                Some(path) => {
+                    tokio::fs::write(&path, &frame).await?;
+                    shared.stats.spilled.fetch_add(1, Ordering::Relaxed);
+                    METRICS.frames_spilled.inc();
+                    shared
+                        .state
+                        .lock()
+                        .unwrap()
+                        .entries
+                        .push_back(Entry::Disk(timestamp_ms, path));
+                    shared.items.notify_one();
+                    return Ok(());
+                }
+                None => space.await,
+            }
+        }
+    }
+}
+
+impl Drop for UploadSender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.items.notify_one();
+    }
+}
+
+/// the transfer task's end of the queue
+pub struct UploadReceiver {
+    shared: Arc<Shared>,
+}
+
+impl UploadReceiver {
+    /// how many frames are waiting, including any spilled to disk
+    pub fn backlog(&self) -> usize {
+        self.shared.state.lock().unwrap().entries.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.config.queue_frames
+    }
+
+    pub fn stats(&self) -> Arc<UploadStats> {
+        self.shared.stats.clone()
+    }
+
+    /// waits for the next frame and its timestamp, or returns None once the sender is dropped and
+    /// everything has been taken
+    pub async fn pop(&mut self) -> Option<Result<(u64, Bytes), std::io::Error>> {
+        let shared = &self.shared;
+        loop {
+            let items = shared.items.notified();
+            let entry = {
+                let mut state = shared.state.lock().unwrap();
+                if state.spooled {
+                    return Some(Err(std::io::Error::new(
+                        ErrorKind::Interrupted,
+                        "the rest of the recording was kept in video_frames",
+                    )));
+                }
+                match state.entries.pop_front() {
+                    Some(entry) => {
+                        if let Entry::Memory(..) = entry {
+                            state.in_memory -= 1;
+                        }
+                        Some(entry)
+                    }
+                    None if state.closed => return None,
+                    None => None,
+                }
+            };
+            match entry {
+                Some(Entry::Memory(timestamp_ms, frame)) => {
+                    shared.space.notify_one();
+                    return Some(Ok((timestamp_ms, frame)));
+                }
+                Some(Entry::Disk(timestamp_ms, path)) => {
+                    let frame = tokio::fs::read(&path).await;
+                    let _ = tokio::fs::remove_file(&path).await;
+                    return Some(frame.map(|frame| (timestamp_ms, Bytes::from(frame))));
+                }
+                None => items.await,
+            }
+        }
+    }
+}
+
+impl Drop for UploadReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receiver_gone = true;
+        // dont leave spilled frames behind, unless they were kept on purpose
+        if state.spooled {
+            state.entries.clear();
+        }
+        for entry in state.entries.drain(..) {
+            if let Entry::Disk(_, path) = entry {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        state.in_memory = 0;
+        drop(state);
+        self.shared.space.notify_one();
+    }
+}
+
+/// see UploadSender::hold
+pub struct QueueHold {
+    shared: Arc<Shared>,
+}
+
+impl Drop for QueueHold {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().held = false;
+    }
+}
+
+/// keeps a recording's queue once it cant be uploaded any more, eg. when the client is stopping
+#[derive(Clone)]
+pub struct QueueSpool {
+    shared: Arc<Shared>,
+}
+
+impl QueueSpool {
+    /// stops the upload taking any more frames and leaves what it hadnt taken on disk, in the order they were
+    /// captured, as {spill_prefix}.kept.{n}.jpg. returns how many frames were kept
+    pub fn spool(&self) -> Result<usize, std::io::Error> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if state.receiver_gone {
+            return Ok(0);
+        }
+        state.spooled = true;
+        state.in_memory = 0;
+        let kept = state.entries.len();
+        for (n, entry) in state.entries.iter_mut().enumerate() {
+            let mut path = shared.spill_prefix.clone().into_os_string();
+            path.push(format!(".kept.{}.jpg", n + 1));
+            let path = PathBuf::from(path);
+            match entry {
+                Entry::Memory(_, frame) => std::fs::write(&path, frame)?,
+                Entry::Disk(_, spilled) => std::fs::rename(&*spilled, &path)?,
+            }
+            let timestamp_ms = match entry {
+                Entry::Memory(timestamp_ms, _) | Entry::Disk(timestamp_ms, _) => *timestamp_ms,
+            };
+            *entry = Entry::Disk(timestamp_ms, path);
+        }
+        drop(state);
+        shared.items.notify_one();
+        shared.space.notify_one();
+        Ok(kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use security_cam_common::shuttle_runtime::tokio;
+    use std::time::Duration;
+
+    fn config(policy: BackpressurePolicy) -> UploadConfig {
+        UploadConfig {
+            policy,
+            queue_frames: 4,
+            ..Default::default()
+        }
+    }
+
+    async fn drain(mut rx: UploadReceiver) -> Vec<u8> {
+        let mut frames = Vec::new();
+        while let Some(frame) = rx.pop().await {
+            let (timestamp_ms, frame) = frame.unwrap();
+            assert_eq!(timestamp_ms, frame[0] as u64 * 40);
+            frames.push(frame[0]);
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let (mut tx, rx) = upload_queue(config(BackpressurePolicy::DropOldest), PathBuf::new());
+        for i in 0..10 {
+            tx.push(vec![i], i as u64 * 40).await.unwrap();
+        }
+        let stats = tx.stats();
+        drop(tx);
+        assert_eq!(drain(rx).await, vec![6, 7, 8, 9]);
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test]
+    async fn test_reduce_frame_rate() {
+        let (mut tx, rx) =
+            upload_queue(config(BackpressurePolicy::ReduceFrameRate), PathBuf::new());
+        for i in 0..10 {
+            tx.push(vec![i], i as u64 * 40).await.unwrap();
+        }
+        let stats = tx.stats();
+        drop(tx);
+        // the first two go straight in, then every other frame until the queue is full
+        assert_eq!(drain(rx).await, vec![0, 1, 3, 5]);
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 6);
+    }
+
+    #[tokio::test]
+    async fn test_spill_to_disk_keeps_order() {
+        let prefix =
+            std::env::temp_dir().join(format!("security-cam-spill-test-{}", std::process::id()));
+        let (mut tx, rx) = upload_queue(config(BackpressurePolicy::SpillToDisk), prefix);
+        for i in 0..10 {
+            tx.push(vec![i], i as u64 * 40).await.unwrap();
+        }
+        let stats = tx.stats();
+        drop(tx);
+        assert_eq!(drain(rx).await, (0..10).collect::<Vec<_>>());
+        assert_eq!(stats.spilled.load(Ordering::Relaxed), 6);
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_spool_keeps_queued_frames() {
+        let prefix =
+            std::env::temp_dir().join(format!("security-cam-spool-test-{}", std::process::id()));
+        let (mut tx, mut rx) =
+            upload_queue(config(BackpressurePolicy::SpillToDisk), prefix.clone());
+        for i in 0..6 {
+            tx.push(vec![i], i as u64 * 40).await.unwrap();
+        }
+        assert_eq!(rx.pop().await.unwrap().unwrap().1[0], 0);
+        assert_eq!(tx.spool().spool().unwrap(), 5);
+
+        // the upload cant take any more, and the frames it didnt get stay on disk after it has gone
+        rx.pop().await.unwrap().unwrap_err();
+        tx.push(vec![6], 240).await.unwrap_err();
+        drop(rx);
+        let mut kept = Vec::new();
+        for n in 1..=5 {
+            let mut path = prefix.clone().into_os_string();
+            path.push(format!(".kept.{n}.jpg"));
+            kept.push(std::fs::read(&path).unwrap()[0]);
+            std::fs::remove_file(&path).unwrap();
+        }
+        assert_eq!(kept, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_held_queue_spills_instead_of_blocking() {
+        let prefix =
+            std::env::temp_dir().join(format!("security-cam-hold-test-{}", std::process::id()));
+        let (mut tx, rx) = upload_queue(config(BackpressurePolicy::Block), prefix);
+        let hold = tx.hold();
+        for i in 0..8 {
+            tokio::time::timeout(Duration::from_millis(50), tx.push(vec![i], i as u64 * 40))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        // once the upload has started, a full queue waits again
+        drop(hold);
+        let stats = tx.stats();
+        tokio::time::timeout(Duration::from_millis(50), tx.push(vec![8], 320))
+            .await
+            .unwrap_err();
+        drop(tx);
+        assert_eq!(drain(rx).await, (0..8).collect::<Vec<_>>());
+        assert_eq!(stats.spilled.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_block_waits_for_space() {
+        let (mut tx, mut rx) = upload_queue(config(BackpressurePolicy::Block), PathBuf::new());
+        for i in 0..4 {
+            tx.push(vec![i], i as u64 * 40).await.unwrap();
+        }
+        tokio::time::timeout(Duration::from_millis(50), tx.push(vec![4], 160))
+            .await
+            .unwrap_err();
+        assert_eq!(rx.pop().await.unwrap().unwrap().1[0], 0);
+        tx.push(vec![5], 200).await.unwrap();
+
+        drop(rx);
+        tx.push(vec![6], 240).await.unwrap_err();
+    }
+}